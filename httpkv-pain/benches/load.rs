@@ -0,0 +1,159 @@
+//! Readers and writers hammering random keys on disk storage, once with everything in
+//! one partition (what we had with a single lock over the whole store) and once split
+//! into partitions. Every write is fsynced, so a writer holds its partition for a while.
+//!
+//! ```text
+//! cargo bench --bench load
+//! ```
+
+use std::{
+    ops::Bound,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use httpkv::{
+    storage::{DiskStorage, Op, Sharded, MAX_PARTITION_KEYS},
+    Item, Meta,
+};
+
+const KEYS: u64 = 50_000;
+const READERS: usize = 8;
+const WRITERS: usize = 8;
+const RUN_FOR: Duration = Duration::from_secs(5);
+
+fn main() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(READERS + WRITERS)
+        .enable_all()
+        .build()?;
+
+    println!(
+        "{} keys, {} readers, {} writers, {:?} each",
+        KEYS, READERS, WRITERS, RUN_FOR
+    );
+    println!(
+        "{:>12} {:>12} {:>12} {:>12}",
+        "partitions", "reads/s", "writes/s", "p99 read"
+    );
+    for max_partition_keys in [usize::MAX, MAX_PARTITION_KEYS] {
+        let result = runtime.block_on(run(max_partition_keys))?;
+        println!(
+            "{:>12} {:>12.0} {:>12.0} {:>12?}",
+            result.partitions, result.reads, result.writes, result.p99_read
+        );
+    }
+    Ok(())
+}
+
+struct Result {
+    partitions: usize,
+    reads: f64,
+    writes: f64,
+    p99_read: Duration,
+}
+
+async fn run(max_partition_keys: usize) -> anyhow::Result<Result> {
+    let dir = tempfile::tempdir()?;
+    let store = Arc::new(Sharded::new(
+        Box::new(DiskStorage::open(dir.path())?),
+        max_partition_keys,
+    ));
+    {
+        let mut kv = store
+            .write_range((Bound::Unbounded, Bound::Unbounded))
+            .await;
+        let version = kv.next_version();
+        kv.apply((0..KEYS).map(|n| put(n, version)).collect())?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writes = Arc::new(AtomicU64::new(0));
+    let mut writers = Vec::new();
+    for seed in 0..WRITERS {
+        let (store, stop, writes) = (store.clone(), stop.clone(), writes.clone());
+        writers.push(tokio::spawn(async move {
+            let mut rng = Rng(seed as u64 + 1);
+            while !stop.load(Ordering::Relaxed) {
+                let n = rng.next() % KEYS;
+                let mut kv = store.write(&[key(n)]).await;
+                let version = kv.next_version();
+                kv.apply(vec![put(n, version)])?;
+                writes.fetch_add(1, Ordering::Relaxed);
+            }
+            anyhow::Ok(())
+        }));
+    }
+    let mut readers = Vec::new();
+    for seed in 0..READERS {
+        let (store, stop) = (store.clone(), stop.clone());
+        readers.push(tokio::spawn(async move {
+            let mut rng = Rng(seed as u64 + 1000);
+            let mut latencies = Vec::new();
+            while !stop.load(Ordering::Relaxed) {
+                let key = key(rng.next() % KEYS);
+                let started = Instant::now();
+                let kv = store.read(&[&key]).await;
+                assert!(kv.get_live(&key).is_some());
+                drop(kv);
+                latencies.push(started.elapsed());
+                // Let the other readers on this thread have a go
+                tokio::task::yield_now().await;
+            }
+            latencies
+        }));
+    }
+
+    tokio::time::sleep(RUN_FOR).await;
+    stop.store(true, Ordering::Relaxed);
+    for writer in writers {
+        writer.await??;
+    }
+    let mut latencies = Vec::new();
+    for reader in readers {
+        latencies.extend(reader.await?);
+    }
+    latencies.sort();
+
+    let secs = RUN_FOR.as_secs_f64();
+    Ok(Result {
+        partitions: store.partition_count(),
+        reads: latencies.len() as f64 / secs,
+        writes: writes.load(Ordering::Relaxed) as f64 / secs,
+        p99_read: latencies
+            .get(latencies.len() * 99 / 100)
+            .copied()
+            .unwrap_or_default(),
+    })
+}
+
+fn key(n: u64) -> Vec<u8> {
+    format!("key/{:08}", n).into_bytes()
+}
+
+fn put(n: u64, version: i64) -> Op {
+    Op::Put {
+        key: key(n),
+        item: Item {
+            version,
+            data: vec![b'x'; 100],
+            expires_at: None,
+            meta: Meta::default(),
+        },
+    }
+}
+
+// xorshift, plenty random enough to pick keys
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
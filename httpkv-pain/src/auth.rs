@@ -0,0 +1,289 @@
+use std::{collections::HashMap, path::Path as FsPath, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use axum::{
+    extract::{MatchedPath, Query, Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+
+use crate::{
+    routes::{key_encoding, show_key, unencode_key, Key},
+    AppError, AppState,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    Read,
+    Write,
+    List,
+}
+
+/// What one token is allowed to do. Each list holds key prefixes, "" covers everything.
+#[derive(Deserialize, Debug, Default)]
+pub struct Grants {
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+    #[serde(default)]
+    list: Vec<String>,
+    /// Backup, restore, compaction and following as a replica
+    #[serde(default)]
+    admin: bool,
+}
+
+impl Grants {
+    /// Whether `key` (or every key starting with it, for lists and prefix ops) is covered
+    pub fn allows(&self, permission: Permission, key: &[u8]) -> bool {
+        let prefixes = match permission {
+            Permission::Read => &self.read,
+            Permission::Write => &self.write,
+            Permission::List => &self.list,
+        };
+        prefixes
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_bytes()))
+    }
+
+    pub fn check(&self, permission: Permission, key: &[u8]) -> Result<(), AppError> {
+        if self.allows(permission, key) {
+            return Ok(());
+        }
+        Err(AppError::CustomCode(
+            anyhow!("Token can't {:?} {}", permission, show_key(key)),
+            StatusCode::FORBIDDEN,
+        ))
+    }
+}
+
+/// Tokens loaded from a TOML file like:
+///
+/// ```toml
+/// [[token]]
+/// token = "s3cret"
+/// read = ["config/"]
+/// list = ["config/"]
+/// write = ["config/staging/"]
+/// ```
+///
+/// `/_metrics` takes an admin token, unless `public_metrics = true` is set at the top
+/// so a scraper can get at it without one.
+#[derive(Debug, Default)]
+pub struct AuthConfig {
+    tokens: HashMap<String, Arc<Grants>>,
+    public_metrics: bool,
+}
+
+#[derive(Deserialize)]
+struct AuthFile {
+    #[serde(default, rename = "token")]
+    tokens: Vec<TokenEntry>,
+    #[serde(default)]
+    public_metrics: bool,
+}
+
+#[derive(Deserialize)]
+struct TokenEntry {
+    token: String,
+    #[serde(flatten)]
+    grants: Grants,
+}
+
+impl AuthConfig {
+    pub fn load(path: impl AsRef<FsPath>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let raw =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let file: AuthFile =
+            toml::from_str(&raw).with_context(|| format!("parsing {}", path.display()))?;
+        Ok(Self {
+            tokens: file
+                .tokens
+                .into_iter()
+                .map(|entry| (entry.token, Arc::new(entry.grants)))
+                .collect(),
+            public_metrics: file.public_metrics,
+        })
+    }
+
+    pub(crate) fn grants(&self, token: &str) -> Option<Arc<Grants>> {
+        self.tokens.get(token.trim()).cloned()
+    }
+}
+
+/// What a request needs, worked out from the route, method and query
+enum Required {
+    Nothing,
+    Admin,
+    // Every one of these for the key or prefix
+    Key(&'static [Permission], Vec<u8>),
+    // The handler checks each key itself, e.g. a txn or a batch
+    PerKey,
+}
+
+/// Rejects requests without a known bearer token, or whose token doesn't cover the
+/// key or prefix they touch. Stashes the token's `Grants` in the request extensions for
+/// handlers that need to check more than one key. A no-op if auth isn't configured.
+pub(crate) async fn authorize(
+    State(state): State<AppState>,
+    matched: MatchedPath,
+    key: Option<Key>,
+    Query(query): Query<HashMap<String, String>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(auth) = &state.auth else {
+        return Ok(next.run(req).await);
+    };
+
+    // A key that doesn't decode is left to the handler to reject
+    let key = key.map(|Key(key)| key);
+    let end = query
+        .get("end")
+        .map(|end| unencode_key(end.clone().into_bytes(), key_encoding(req.uri())))
+        .transpose()?;
+    let required = required(req.method(), matched.as_str(), key, end, &query, auth);
+    if let Required::Nothing = required {
+        return Ok(next.run(req).await);
+    }
+
+    let grants = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| auth.grants(token))
+        .ok_or_else(|| {
+            AppError::CustomCode(
+                anyhow!("Missing or unknown bearer token"),
+                StatusCode::UNAUTHORIZED,
+            )
+        })?;
+
+    match required {
+        Required::Admin if !grants.admin => {
+            return Err(AppError::CustomCode(
+                anyhow!("Token isn't an admin"),
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        Required::Key(permissions, key) => {
+            for permission in permissions {
+                grants.check(*permission, &key)?;
+            }
+        }
+        _ => {}
+    }
+
+    req.extensions_mut().insert(grants);
+    Ok(next.run(req).await)
+}
+
+fn required(
+    method: &Method,
+    route: &str,
+    key: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    query: &HashMap<String, String>,
+    auth: &AuthConfig,
+) -> Required {
+    match route {
+        "/_metrics" if auth.public_metrics => return Required::Nothing,
+        "/_metrics" => return Required::Admin,
+        "/_txn" | "/_mget" | "/_mput" | "/_query" => return Required::PerKey,
+        route if route.starts_with("/_uploads") => return Required::PerKey,
+        "/_compact" | "/_backup" | "/_restore" | "/_changes" | "/_quotas" => {
+            return Required::Admin
+        }
+        _ => {}
+    }
+
+    let key = key.unwrap_or_default();
+    if *method == Method::GET || *method == Method::HEAD {
+        if lists(method, route, query) {
+            if query.contains_key("vals") || query.contains_key("with_vals") {
+                Required::Key(&[Permission::List, Permission::Read], key)
+            } else {
+                Required::Key(&[Permission::List], key)
+            }
+        } else if route == "/" && !query.contains_key("watch") {
+            // Health check
+            Required::Nothing
+        } else {
+            Required::Key(&[Permission::Read], key)
+        }
+    } else if let Some(end) = end.filter(|_| *method == Method::DELETE) {
+        // Everything in [key, end) starts with their common prefix
+        let common = key.iter().zip(&end).take_while(|(a, b)| a == b).count();
+        Required::Key(&[Permission::Write], key[..common].to_vec())
+    } else {
+        Required::Key(&[Permission::Write], key)
+    }
+}
+
+/// Whether the GET is a listing, the same way `get_or_list_prefix` decides: a watch
+/// wins, then only an empty `list` lists. Anything else reads the key.
+fn lists(method: &Method, route: &str, query: &HashMap<String, String>) -> bool {
+    if query.contains_key("watch") || (*method == Method::HEAD && route == "/:key") {
+        return false;
+    }
+    query.get("list").is_some_and(|list| list.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn needs(method: Method, route: &str, query: &[(&str, &str)]) -> Vec<Permission> {
+        let query = query
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let auth = AuthConfig::default();
+        match required(
+            &method,
+            route,
+            Some(b"secret".to_vec()),
+            None,
+            &query,
+            &auth,
+        ) {
+            Required::Key(permissions, key) => {
+                assert_eq!(key, b"secret");
+                permissions.to_vec()
+            }
+            _ => panic!("expected a key permission"),
+        }
+    }
+
+    #[test]
+    fn requires_what_the_handler_does() {
+        use Permission::*;
+        assert_eq!(needs(Method::GET, "/:key", &[("list", "")]), [List]);
+        // A non-empty list reads the key
+        assert_eq!(needs(Method::GET, "/:key", &[("list", "1")]), [Read]);
+        assert_eq!(needs(Method::HEAD, "/:key", &[("list", "")]), [Read]);
+        assert_eq!(
+            needs(Method::GET, "/:key", &[("list", ""), ("vals", "")]),
+            [List, Read]
+        );
+        assert_eq!(
+            needs(Method::GET, "/:key", &[("list", ""), ("watch", "")]),
+            [Read]
+        );
+    }
+
+    #[test]
+    fn metrics_are_admin_unless_public() {
+        let query = HashMap::new();
+        let mut auth = AuthConfig::default();
+        let metrics =
+            |auth: &AuthConfig| required(&Method::GET, "/_metrics", None, None, &query, auth);
+        assert!(matches!(metrics(&auth), Required::Admin));
+        auth.public_metrics = true;
+        assert!(matches!(metrics(&auth), Required::Nothing));
+    }
+}
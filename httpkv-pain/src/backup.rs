@@ -0,0 +1,271 @@
+//! Backup stream format. All integers are little endian.
+//!
+//! ```text
+//! header:  magic "HTTPKVB2" (8 bytes) | revision i64
+//! entry:   body_len u32 | crc32(body) u32 | body
+//!   body:  key_len u32 | key | version i64 | expires_at i64 (unix millis, -1 for none)
+//!          | meta | data
+//!   meta:  content_type_len u32 (0xFFFFFFFF for none) | content_type
+//!          | field count u32 | (name_len u32 | name | value_len u32 | value) per field
+//! trailer: 0xFFFFFFFF u32 | entry count u64 | crc32 of every byte before this field u32
+//! ```
+//!
+//! Entries come in key order. `revision` is the store's revision when the backup started.
+//! It's read a page at a time, so an entry written while it ran can be newer than that.
+//! "HTTPKVB1" backups are the same without `meta`, and still restore.
+
+use anyhow::{anyhow, bail};
+
+use crate::{Item, Meta};
+
+pub const MAGIC: &[u8; 8] = b"HTTPKVB2";
+// Restores are decoded in memory, so this is as big as one can be
+pub const MAX_RESTORE_BYTES: usize = 256 << 20;
+const MAGIC_V1: &[u8; 8] = b"HTTPKVB1";
+const NONE_LEN: u32 = u32::MAX;
+const END_MARKER: u32 = u32::MAX;
+const HEADER_LEN: usize = 16;
+
+pub struct Backup {
+    pub revision: i64,
+    pub entries: Vec<(Vec<u8>, Item)>,
+}
+
+/// Writes the backup stream a piece at a time, so the handler can send it as it goes:
+/// the header from `new`, then each entry, then `finish` for the trailer.
+pub struct Encoder {
+    count: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl Encoder {
+    /// Starts a backup of the store as of `revision`, returning the header
+    pub fn new(revision: i64) -> (Self, Vec<u8>) {
+        let mut encoder = Self {
+            count: 0,
+            hasher: crc32fast::Hasher::new(),
+        };
+        let mut header = MAGIC.to_vec();
+        header.extend(revision.to_le_bytes());
+        encoder.hasher.update(&header);
+        (encoder, header)
+    }
+
+    pub fn entry(&mut self, key: &[u8], item: &Item) -> Vec<u8> {
+        let entry = encode_entry(key, item);
+        self.count += 1;
+        self.hasher.update(&entry);
+        entry
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut trailer = END_MARKER.to_le_bytes().to_vec();
+        trailer.extend(self.count.to_le_bytes());
+        self.hasher.update(&trailer);
+        trailer.extend(self.hasher.finalize().to_le_bytes());
+        trailer
+    }
+}
+
+fn encode_entry(key: &[u8], item: &Item) -> Vec<u8> {
+    let mut body = Vec::with_capacity(4 + key.len() + 16 + item.data.len());
+    body.extend((key.len() as u32).to_le_bytes());
+    body.extend(key);
+    body.extend(item.version.to_le_bytes());
+    body.extend(item.expires_at.unwrap_or(-1).to_le_bytes());
+    match &item.meta.content_type {
+        Some(content_type) => put_bytes(&mut body, content_type.as_bytes()),
+        None => body.extend(NONE_LEN.to_le_bytes()),
+    }
+    body.extend((item.meta.fields.len() as u32).to_le_bytes());
+    for (name, value) in &item.meta.fields {
+        put_bytes(&mut body, name.as_bytes());
+        put_bytes(&mut body, value.as_bytes());
+    }
+    body.extend(&item.data);
+
+    let mut entry = Vec::with_capacity(8 + body.len());
+    entry.extend((body.len() as u32).to_le_bytes());
+    entry.extend(crc32fast::hash(&body).to_le_bytes());
+    entry.extend(body);
+    entry
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend((bytes.len() as u32).to_le_bytes());
+    buf.extend(bytes);
+}
+
+/// Decodes and verifies a whole backup stream
+pub fn decode(buf: &[u8]) -> anyhow::Result<Backup> {
+    if buf.len() < HEADER_LEN {
+        bail!("not an httpkv backup");
+    }
+    let with_meta = match &buf[..8] {
+        magic if magic == MAGIC => true,
+        magic if magic == MAGIC_V1 => false,
+        _ => bail!("not an httpkv backup"),
+    };
+    let revision = read_i64(buf, 8)?;
+
+    let mut entries = Vec::new();
+    let mut offset = HEADER_LEN;
+    loop {
+        let len = read_u32(buf, offset)?;
+        if len == END_MARKER {
+            break;
+        }
+        let crc = read_u32(buf, offset + 4)?;
+        let body = buf
+            .get(offset + 8..offset + 8 + len as usize)
+            .ok_or_else(|| anyhow!("truncated entry at byte {}", offset))?;
+        if crc32fast::hash(body) != crc {
+            bail!("checksum mismatch in entry at byte {}", offset);
+        }
+        entries.push(decode_entry(body, with_meta)?);
+        offset += 8 + len as usize;
+    }
+
+    let count = read_u64(buf, offset + 4)?;
+    let crc = read_u32(buf, offset + 12)?;
+    if count != entries.len() as u64 {
+        bail!("trailer says {} entries, found {}", count, entries.len());
+    }
+    if crc32fast::hash(&buf[..offset + 12]) != crc {
+        bail!("backup checksum mismatch");
+    }
+    if buf.len() != offset + 16 {
+        bail!("trailing data after the end of the backup");
+    }
+
+    Ok(Backup { revision, entries })
+}
+
+fn decode_entry(body: &[u8], with_meta: bool) -> anyhow::Result<(Vec<u8>, Item)> {
+    let key_len = read_u32(body, 0)? as usize;
+    let key = body
+        .get(4..4 + key_len)
+        .ok_or_else(|| anyhow!("truncated key"))?;
+    let key = key.to_vec();
+    let version = read_i64(body, 4 + key_len)?;
+    let expires_at = match read_i64(body, 12 + key_len)? {
+        -1 => None,
+        at => Some(at),
+    };
+    let mut offset = 20 + key_len;
+    let mut meta = Meta::default();
+    if with_meta {
+        if read_u32(body, offset)? == NONE_LEN {
+            offset += 4;
+        } else {
+            meta.content_type = Some(read_string(body, &mut offset)?);
+        }
+        let fields = read_u32(body, offset)?;
+        offset += 4;
+        for _ in 0..fields {
+            let name = read_string(body, &mut offset)?;
+            let value = read_string(body, &mut offset)?;
+            meta.fields.insert(name, value);
+        }
+    }
+    let data = body
+        .get(offset..)
+        .ok_or_else(|| anyhow!("truncated entry"))?
+        .to_vec();
+    Ok((
+        key,
+        Item {
+            version,
+            data,
+            expires_at,
+            meta,
+        },
+    ))
+}
+
+/// A length prefixed string at `offset`, moving `offset` past it
+fn read_string(buf: &[u8], offset: &mut usize) -> anyhow::Result<String> {
+    let len = read_u32(buf, *offset)? as usize;
+    let bytes = buf
+        .get(*offset + 4..*offset + 4 + len)
+        .ok_or_else(|| anyhow!("truncated backup"))?;
+    *offset += 4 + len;
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
+
+fn read_u32(buf: &[u8], at: usize) -> anyhow::Result<u32> {
+    let bytes = buf
+        .get(at..at + 4)
+        .ok_or_else(|| anyhow!("truncated backup"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(buf: &[u8], at: usize) -> anyhow::Result<u64> {
+    let bytes = buf
+        .get(at..at + 8)
+        .ok_or_else(|| anyhow!("truncated backup"))?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_i64(buf: &[u8], at: usize) -> anyhow::Result<i64> {
+    Ok(read_u64(buf, at)? as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_catches_corruption() {
+        let entries = vec![
+            (
+                b"a".to_vec(),
+                Item {
+                    version: 3,
+                    data: b"one".to_vec(),
+                    expires_at: None,
+                    meta: Meta::default(),
+                },
+            ),
+            (
+                vec![b'b', 0xff],
+                Item {
+                    version: 7,
+                    data: vec![0, 255, 10],
+                    expires_at: Some(1234),
+                    meta: Meta {
+                        content_type: Some("application/octet-stream".to_string()),
+                        fields: [("owner".to_string(), "bob".to_string())].into(),
+                    },
+                },
+            ),
+        ];
+        let (mut encoder, mut buf) = Encoder::new(9);
+        for (key, item) in &entries {
+            buf.extend(encoder.entry(key, item));
+        }
+        buf.extend(encoder.finish());
+
+        let backup = decode(&buf).unwrap();
+        assert_eq!(backup.revision, 9);
+        assert_eq!(backup.entries.len(), 2);
+        assert_eq!(backup.entries[1].0, vec![b'b', 0xff]);
+        assert_eq!(backup.entries[1].1.data, vec![0, 255, 10]);
+        assert_eq!(backup.entries[1].1.expires_at, Some(1234));
+        assert_eq!(backup.entries[0].1.meta, Meta::default());
+        assert_eq!(
+            backup.entries[1]
+                .1
+                .meta
+                .fields
+                .get("owner")
+                .map(String::as_str),
+            Some("bob")
+        );
+
+        let mut corrupt = buf.clone();
+        corrupt[HEADER_LEN + 10] ^= 1;
+        assert!(decode(&corrupt).is_err());
+        assert!(decode(&buf[..buf.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+//! Typed async client for an httpkv server.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), httpkv::client::Error> {
+//! use httpkv::client::{Client, PutOptions};
+//!
+//! let client = Client::new("http://localhost:8080")?;
+//! let version = client.put("config", "v1", PutOptions::default()).await?;
+//! // Only overwrite if nobody else has since
+//! client
+//!     .put("config", "v2", PutOptions { version: Some(version), ..Default::default() })
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{ops::Range, time::Duration};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use futures::{stream, Stream, TryStreamExt};
+use reqwest::{header, RequestBuilder, Response, StatusCode, Url};
+use serde::Deserialize;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("key not found")]
+    NotFound,
+    /// A write condition (nx, ix, version) didn't hold
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("server returned {status}: {message}")]
+    Status { status: u16, message: String },
+    #[error("invalid response: {0}")]
+    InvalidResponse(String),
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+/// A value and the version that wrote it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Value {
+    pub data: Vec<u8>,
+    pub version: i64,
+    /// Seconds until it expires, if it has a TTL
+    pub ttl: Option<i64>,
+}
+
+/// Conditions and expiry for a put, same as the query params on a write
+#[derive(Debug, Clone, Default)]
+pub struct PutOptions {
+    /// Only write if the key doesn't exist
+    pub nx: bool,
+    /// Only write if the key exists
+    pub ix: bool,
+    /// Only write if the key is at this version
+    pub version: Option<i64>,
+    /// Seconds from now
+    pub ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListOptions {
+    /// Keys fetched per request
+    pub page_size: usize,
+    pub with_values: bool,
+    pub reverse: bool,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self {
+            page_size: 100,
+            with_values: false,
+            reverse: false,
+        }
+    }
+}
+
+/// One key from a list, `version` and `value` are only set `with_values`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub version: Option<i64>,
+    pub value: Option<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct Page {
+    pub entries: Vec<Entry>,
+    /// Pass to the next `list_page` call, None on the last page
+    pub next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawPage {
+    items: Vec<RawEntry>,
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawEntry {
+    key: String,
+    key_encoding: Option<String>,
+    version: Option<i64>,
+    value: Option<String>,
+    encoding: Option<String>,
+}
+
+/// Cheap to clone, clones share a connection pool
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base: Url,
+    token: Option<String>,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl Client {
+    /// `base_url` has to be http or https, e.g. `http://localhost:8080`
+    pub fn new(base_url: &str) -> Result<Self, Error> {
+        let base = Url::parse(base_url).map_err(|e| Error::InvalidUrl(e.to_string()))?;
+        if base.cannot_be_a_base() || !matches!(base.scheme(), "http" | "https") {
+            return Err(Error::InvalidUrl(format!(
+                "{} isn't an http or https url",
+                base_url
+            )));
+        }
+        Ok(Self {
+            http: reqwest::Client::new(),
+            base,
+            token: None,
+            retries: 3,
+            backoff: Duration::from_millis(100),
+        })
+    }
+
+    /// Sent as a bearer token on every request
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// How many times to retry a request that didn't get through, starting at
+    /// `backoff` and doubling each time
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    pub async fn get(&self, key: impl AsRef<[u8]>) -> Result<Value, Error> {
+        let url = self.key_url(key);
+        let res = self.send(true, || self.http.get(url.clone())).await?;
+        value_from(res).await
+    }
+
+    /// The bytes of the value in `range`, clamped to its length
+    pub async fn get_range(
+        &self,
+        key: impl AsRef<[u8]>,
+        range: Range<u64>,
+    ) -> Result<Value, Error> {
+        if range.is_empty() {
+            return Err(Error::InvalidResponse("empty range".to_string()));
+        }
+        let url = self.key_url(key);
+        let header = format!("bytes={}-{}", range.start, range.end - 1);
+        let res = self
+            .send(true, || {
+                self.http
+                    .get(url.clone())
+                    .header(header::RANGE, header.clone())
+            })
+            .await?;
+        value_from(res).await
+    }
+
+    /// Returns the new version
+    pub async fn put(
+        &self,
+        key: impl AsRef<[u8]>,
+        data: impl Into<Vec<u8>>,
+        options: PutOptions,
+    ) -> Result<i64, Error> {
+        let mut url = self.key_url(key);
+        {
+            let mut query = url.query_pairs_mut();
+            if options.nx {
+                query.append_key_only("nx");
+            }
+            if options.ix {
+                query.append_key_only("ix");
+            }
+            if let Some(version) = options.version {
+                query.append_pair("version", &version.to_string());
+            }
+            if let Some(ttl) = options.ttl {
+                query.append_pair("ttl", &ttl.to_string());
+            }
+        }
+        let data = data.into();
+        let res = self
+            .send(false, || self.http.post(url.clone()).body(data.clone()))
+            .await?;
+        header_i64(&res, "version")
+            .ok_or_else(|| Error::InvalidResponse("missing version header".to_string()))
+    }
+
+    /// Whether there was anything to delete
+    pub async fn delete(&self, key: impl AsRef<[u8]>) -> Result<bool, Error> {
+        let url = self.key_url(key);
+        let res = self.send(false, || self.http.delete(url.clone())).await?;
+        Ok(res.text().await? != "0")
+    }
+
+    /// One page of keys starting with `prefix`, after the `next` of the previous page
+    pub async fn list_page(
+        &self,
+        prefix: impl AsRef<[u8]>,
+        options: &ListOptions,
+        after: Option<&str>,
+    ) -> Result<Page, Error> {
+        let mut url = self.key_url(prefix);
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_key_only("list");
+            query.append_pair("limit", &options.page_size.to_string());
+            if options.with_values {
+                query.append_key_only("vals");
+            }
+            if options.reverse {
+                query.append_key_only("reverse");
+            }
+            if let Some(after) = after {
+                query.append_pair("after", after);
+            }
+        }
+        let res = self
+            .send(true, || {
+                self.http
+                    .get(url.clone())
+                    .header(header::ACCEPT, "application/json")
+            })
+            .await?;
+        let raw: RawPage = res.json().await?;
+        let entries = raw
+            .items
+            .into_iter()
+            .map(|raw| {
+                let value = raw
+                    .value
+                    .map(|value| decode(value, raw.encoding.as_deref()))
+                    .transpose()?;
+                Ok(Entry {
+                    key: decode(raw.key, raw.key_encoding.as_deref())?,
+                    version: raw.version,
+                    value,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Page {
+            entries,
+            next: raw.next,
+        })
+    }
+
+    /// Every key starting with `prefix`, fetching pages as the stream is read
+    pub fn list(
+        &self,
+        prefix: impl AsRef<[u8]>,
+        options: ListOptions,
+    ) -> impl Stream<Item = Result<Entry, Error>> + '_ {
+        let prefix = prefix.as_ref().to_vec();
+        // None once we've fetched the last page
+        stream::try_unfold(Some(None), move |after: Option<Option<String>>| {
+            let options = options.clone();
+            let prefix = prefix.clone();
+            async move {
+                let Some(after) = after else {
+                    return Ok::<_, Error>(None);
+                };
+                let page = self.list_page(prefix, &options, after.as_deref()).await?;
+                let next = page.next.map(Some);
+                Ok(Some((stream::iter(page.entries.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    fn key_url(&self, key: impl AsRef<[u8]>) -> Url {
+        let key = key.as_ref();
+        let mut url = self.base.clone();
+        if key.is_empty() {
+            return url;
+        }
+        let mut segments = url
+            .path_segments_mut()
+            .expect("new checked it can be a base");
+        // Percent-encodes anything that isn't allowed in a path segment, '/' included.
+        // Keys that aren't UTF-8 go as base64 instead.
+        let encoded = match std::str::from_utf8(key) {
+            Ok(key) => {
+                segments.pop_if_empty().push(key);
+                false
+            }
+            Err(_) => {
+                segments.pop_if_empty().push(&URL_SAFE_NO_PAD.encode(key));
+                true
+            }
+        };
+        drop(segments);
+        if encoded {
+            url.query_pairs_mut().append_pair("key_encoding", "base64");
+        }
+        url
+    }
+
+    /// Sends the request, retrying with backoff if it never reached the server or the
+    /// server was unavailable. Reads also retry timeouts, writes don't since they may
+    /// have been applied.
+    async fn send(
+        &self,
+        idempotent: bool,
+        build: impl Fn() -> RequestBuilder,
+    ) -> Result<Response, Error> {
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        loop {
+            let mut req = build();
+            if let Some(token) = &self.token {
+                req = req.bearer_auth(token);
+            }
+            let result = req.send().await;
+            let retry = match &result {
+                Err(e) => e.is_connect() || (idempotent && e.is_timeout()),
+                Ok(res) => matches!(
+                    res.status(),
+                    StatusCode::BAD_GATEWAY
+                        | StatusCode::SERVICE_UNAVAILABLE
+                        | StatusCode::GATEWAY_TIMEOUT
+                ),
+            };
+            if !retry || attempt >= self.retries {
+                return check(result?).await;
+            }
+            attempt += 1;
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+async fn check(res: Response) -> Result<Response, Error> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    let message = res.text().await.unwrap_or_default();
+    Err(match status {
+        StatusCode::NOT_FOUND => Error::NotFound,
+        StatusCode::CONFLICT => Error::Conflict(message),
+        _ => Error::Status {
+            status: status.as_u16(),
+            message,
+        },
+    })
+}
+
+async fn value_from(res: Response) -> Result<Value, Error> {
+    let version = header_i64(&res, "version")
+        .ok_or_else(|| Error::InvalidResponse("missing version header".to_string()))?;
+    let ttl = header_i64(&res, "ttl");
+    Ok(Value {
+        data: res.bytes().await?.to_vec(),
+        version,
+        ttl,
+    })
+}
+
+fn header_i64(res: &Response, name: &str) -> Option<i64> {
+    res.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// A key or value from a JSON response, with its `encoding`
+fn decode(value: String, encoding: Option<&str>) -> Result<Vec<u8>, Error> {
+    match encoding {
+        Some("base64") => STANDARD
+            .decode(value)
+            .map_err(|e| Error::InvalidResponse(e.to_string())),
+        _ => Ok(value.into_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn wants_an_http_base_url() {
+        for bad in ["localhost:8080", "mailto:a@b", "ftp://host/"] {
+            assert!(
+                matches!(Client::new(bad), Err(Error::InvalidUrl(_))),
+                "{}",
+                bad
+            );
+        }
+        let client = Client::new("https://host/kv/").unwrap();
+        assert_eq!(client.key_url("a/b").as_str(), "https://host/kv/a%2Fb");
+    }
+
+    #[tokio::test]
+    async fn talks_to_a_server() -> Result<(), Error> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+        tokio::spawn(async move {
+            crate::start(
+                crate::Options {
+                    addr,
+                    ..Default::default()
+                },
+                Box::new(MemoryStorage::new()),
+                std::future::pending(),
+            )
+            .await
+        });
+        // The first request retries until the server is up
+        let client = Client::new(&format!("http://127.0.0.1:{}", port))?
+            .with_retries(20, Duration::from_millis(10));
+
+        assert!(matches!(client.get("a").await, Err(Error::NotFound)));
+        let v1 = client.put("a", "hello", PutOptions::default()).await?;
+        let nx = PutOptions {
+            nx: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            client.put("a", "again", nx).await,
+            Err(Error::Conflict(_))
+        ));
+        let stale = PutOptions {
+            version: Some(v1 - 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            client.put("a", "again", stale).await,
+            Err(Error::Conflict(_))
+        ));
+
+        let value = client.get("a").await?;
+        assert_eq!(value.data, b"hello");
+        assert_eq!(value.version, v1);
+        assert_eq!(client.get_range("a", 1..3).await?.data, b"el");
+
+        for key in ["p/1", "p/2", "p/3", "p/4", "p/5", "q"] {
+            client.put(key, key, PutOptions::default()).await?;
+        }
+        let options = ListOptions {
+            page_size: 2,
+            with_values: true,
+            ..Default::default()
+        };
+        let listed: Vec<Entry> = client.list("p/", options).try_collect().await?;
+        let keys: Vec<&[u8]> = listed.iter().map(|e| e.key.as_slice()).collect();
+        assert_eq!(keys, [b"p/1", b"p/2", b"p/3", b"p/4", b"p/5"]);
+        assert_eq!(listed[4].value.as_deref(), Some(&b"p/5"[..]));
+
+        let binary = [b'p', b'/', 0xff, 0x00];
+        client.put(binary, "bin", PutOptions::default()).await?;
+        assert_eq!(client.get(binary).await?.data, b"bin");
+        let listed: Vec<Entry> = client
+            .list("p/", ListOptions::default())
+            .try_collect()
+            .await?;
+        assert_eq!(listed.last().map(|e| e.key.as_slice()), Some(&binary[..]));
+
+        assert!(client.delete("a").await?);
+        assert!(!client.delete("a").await?);
+        Ok(())
+    }
+}
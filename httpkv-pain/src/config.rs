@@ -0,0 +1,209 @@
+//! Settings for the `httpkv` binary. Each one comes from the first of: a command line
+//! flag, an `HTTPKV_*` environment variable, the TOML file given by `--config`, or the
+//! default. For example:
+//!
+//! ```toml
+//! listen = "0.0.0.0:8080"
+//! resp_listen = "0.0.0.0:6379"
+//! data_dir = "/var/lib/httpkv"
+//! auth_file = "/etc/httpkv/auth.toml"
+//! log_level = "info"
+//! log_format = "json"
+//! body_limit = 1048576
+//! ```
+
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use tracing::Level;
+
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:8080";
+pub const DEFAULT_BODY_LIMIT: usize = 99_000;
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Compact,
+    Json,
+}
+
+/// What was given on the command line, in the environment or in the file. Anything
+/// left unset falls through to the next of those, then to the default in [`Config`].
+#[derive(Parser, Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+#[command(version, about = "A key-value store over HTTP")]
+struct Settings {
+    /// TOML file with any of the settings below
+    #[arg(long, env = "HTTPKV_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+
+    /// Address to serve HTTP on [default: 0.0.0.0:8080]
+    #[arg(long, env = "HTTPKV_ADDR")]
+    listen: Option<String>,
+
+    /// Also speak the Redis protocol on this address
+    #[arg(long, env = "HTTPKV_RESP_ADDR")]
+    resp_listen: Option<String>,
+
+    /// Keep data here across restarts, otherwise it's all in memory
+    #[arg(long, env = "HTTPKV_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Require bearer tokens from this file, otherwise anyone can do anything
+    #[arg(long, env = "HTTPKV_AUTH_FILE")]
+    auth_file: Option<PathBuf>,
+
+    /// Index fields of JSON values for /_query, as declared in this file
+    #[arg(long, env = "HTTPKV_INDEX_FILE")]
+    index_file: Option<PathBuf>,
+
+    /// Limit what each prefix can store and how fast it writes, as declared in this file
+    #[arg(long, env = "HTTPKV_QUOTA_FILE")]
+    quota_file: Option<PathBuf>,
+
+    /// Run as a read-only follower of the httpkv at this URL
+    #[arg(long, env = "HTTPKV_LEADER_URL")]
+    leader_url: Option<String>,
+
+    /// Bearer token for the leader, it needs to be an admin
+    #[arg(long, env = "HTTPKV_LEADER_TOKEN")]
+    leader_token: Option<String>,
+
+    /// trace, debug, info, warn or error [default: debug]
+    #[arg(long, env = "HTTPKV_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// [default: compact]
+    #[arg(long, env = "HTTPKV_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Largest request body in bytes, except for upload chunks [default: 99000]
+    #[arg(long, env = "HTTPKV_BODY_LIMIT")]
+    body_limit: Option<usize>,
+
+    /// Seconds to wait for requests to finish on shutdown [default: 30]
+    #[arg(long, env = "HTTPKV_DRAIN_SECS")]
+    drain_secs: Option<u64>,
+}
+
+impl Settings {
+    /// Fills in whatever's unset here from `other`
+    fn or(self, other: Settings) -> Settings {
+        Settings {
+            config: self.config.or(other.config),
+            listen: self.listen.or(other.listen),
+            resp_listen: self.resp_listen.or(other.resp_listen),
+            data_dir: self.data_dir.or(other.data_dir),
+            auth_file: self.auth_file.or(other.auth_file),
+            index_file: self.index_file.or(other.index_file),
+            quota_file: self.quota_file.or(other.quota_file),
+            leader_url: self.leader_url.or(other.leader_url),
+            leader_token: self.leader_token.or(other.leader_token),
+            log_level: self.log_level.or(other.log_level),
+            log_format: self.log_format.or(other.log_format),
+            body_limit: self.body_limit.or(other.body_limit),
+            drain_secs: self.drain_secs.or(other.drain_secs),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub listen: String,
+    pub resp_listen: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub auth_file: Option<PathBuf>,
+    pub index_file: Option<PathBuf>,
+    pub quota_file: Option<PathBuf>,
+    pub leader_url: Option<String>,
+    pub leader_token: Option<String>,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    pub body_limit: usize,
+    pub drain_timeout: Duration,
+}
+
+impl Config {
+    /// From this process's arguments and environment, and the file they point at.
+    /// Exits with usage on bad arguments, like any CLI.
+    pub fn load() -> anyhow::Result<Self> {
+        Self::from_settings(Settings::parse())
+    }
+
+    fn from_settings(args: Settings) -> anyhow::Result<Self> {
+        let file = match &args.config {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path)
+                    .with_context(|| format!("reading {}", path.display()))?;
+                toml::from_str(&raw).with_context(|| format!("parsing {}", path.display()))?
+            }
+            None => Settings::default(),
+        };
+        let settings = args.or(file);
+        Ok(Self {
+            listen: settings
+                .listen
+                .unwrap_or_else(|| DEFAULT_LISTEN.to_string()),
+            resp_listen: settings.resp_listen,
+            data_dir: settings.data_dir,
+            auth_file: settings.auth_file,
+            index_file: settings.index_file,
+            quota_file: settings.quota_file,
+            leader_url: settings.leader_url,
+            leader_token: settings.leader_token,
+            log_level: match settings.log_level {
+                Some(level) => level
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid log level {:?}", level))?,
+                None => Level::DEBUG,
+            },
+            log_format: settings.log_format.unwrap_or_default(),
+            body_limit: settings.body_limit.unwrap_or(DEFAULT_BODY_LIMIT),
+            drain_timeout: settings
+                .drain_secs
+                .map_or(DEFAULT_DRAIN_TIMEOUT, Duration::from_secs),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_win_over_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("httpkv.toml");
+        std::fs::write(
+            &path,
+            "listen = \"127.0.0.1:1\"\nlog_format = \"json\"\nbody_limit = 5\n",
+        )
+        .unwrap();
+
+        let args = Settings::try_parse_from([
+            "httpkv",
+            "--config",
+            path.to_str().unwrap(),
+            "--listen",
+            "127.0.0.1:2",
+            "--log-level",
+            "warn",
+        ])
+        .unwrap();
+        let config = Config::from_settings(args).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:2");
+        assert_eq!(config.log_level, Level::WARN);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.body_limit, 5);
+        assert_eq!(config.drain_timeout, DEFAULT_DRAIN_TIMEOUT);
+
+        std::fs::write(&path, "listen_on = \"oops\"\n").unwrap();
+        let args = Settings::try_parse_from(["httpkv", "--config", path.to_str().unwrap()]);
+        assert!(Config::from_settings(args.unwrap()).is_err());
+    }
+}
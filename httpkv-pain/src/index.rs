@@ -0,0 +1,480 @@
+//! Secondary indexes on fields of JSON values, declared per key prefix.
+//!
+//! They only live in memory: they're built from the store on startup and kept up to
+//! date by [`Sharded`](crate::storage::Sharded) as it applies each batch, under the same
+//! partition locks as the keys themselves. So anyone holding a view over a range of
+//! keys sees the indexes agree with it for that range.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap},
+    ops::Bound,
+    path::Path,
+    sync::RwLock,
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{storage::prefix_end, Item};
+
+/// Declarations loaded from a TOML file like:
+///
+/// ```toml
+/// [[index]]
+/// prefix = "jobs/"
+/// fields = ["status", "attempts", "owner.team"]
+/// ```
+///
+/// Nested fields are separated by dots.
+#[derive(Deserialize, Debug, Default)]
+pub struct IndexConfig {
+    #[serde(default, rename = "index")]
+    indexes: Vec<IndexDecl>,
+}
+
+#[derive(Deserialize, Debug)]
+struct IndexDecl {
+    prefix: String,
+    fields: Vec<String>,
+}
+
+impl IndexConfig {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let raw =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        toml::from_str(&raw).with_context(|| format!("parsing {}", path.display()))
+    }
+}
+
+/// A JSON value we can index and order. Values of different types never compare equal,
+/// and a range only matches values of its own type.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Number(f64);
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl Scalar {
+    fn from_json(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(Self::Null),
+            Value::Bool(b) => Some(Self::Bool(*b)),
+            Value::Number(n) => n.as_f64().map(|n| Self::Number(Number(n))),
+            Value::String(s) => Some(Self::String(s.clone())),
+            // Arrays and objects aren't indexed
+            _ => None,
+        }
+    }
+
+    fn same_type(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/// The value of a dotted `field` in a JSON document
+fn lookup(doc: &Value, field: &str) -> Option<Scalar> {
+    field
+        .split('.')
+        .try_fold(doc, |value, part| value.get(part))
+        .and_then(Scalar::from_json)
+}
+
+fn parse_doc(item: &Item) -> Option<Value> {
+    serde_json::from_slice(&item.data).ok()
+}
+
+#[derive(Debug)]
+struct Index {
+    prefix: Vec<u8>,
+    field: String,
+    entries: BTreeMap<Scalar, BTreeSet<Vec<u8>>>,
+}
+
+impl Index {
+    fn insert(&mut self, key: &[u8], doc: &Value) {
+        if let Some(value) = lookup(doc, &self.field) {
+            self.entries.entry(value).or_default().insert(key.to_vec());
+        }
+    }
+
+    fn remove(&mut self, key: &[u8], doc: &Value) {
+        if let Some(value) = lookup(doc, &self.field) {
+            if let Some(keys) = self.entries.get_mut(&value) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.entries.remove(&value);
+                }
+            }
+        }
+    }
+}
+
+/// Every declared index. Updated with each batch the store applies.
+#[derive(Debug, Default)]
+pub struct Indexes {
+    indexes: RwLock<Vec<Index>>,
+}
+
+impl Indexes {
+    pub fn new(config: IndexConfig) -> Self {
+        let indexes = config
+            .indexes
+            .into_iter()
+            .flat_map(|decl| {
+                decl.fields.into_iter().map(move |field| Index {
+                    prefix: decl.prefix.clone().into_bytes(),
+                    field,
+                    entries: BTreeMap::new(),
+                })
+            })
+            .collect();
+        Self {
+            indexes: RwLock::new(indexes),
+        }
+    }
+
+    /// `key` is changing from `old` to `new`, either of which can be None
+    pub(crate) fn update(&self, key: &[u8], old: Option<&Item>, new: Option<&Item>) {
+        let mut indexes = self.indexes.write().unwrap();
+        let mut covering = indexes
+            .iter_mut()
+            .filter(|index| key.starts_with(&index.prefix))
+            .peekable();
+        if covering.peek().is_none() {
+            return;
+        }
+        let old = old.and_then(parse_doc);
+        let new = new.and_then(parse_doc);
+        for index in covering {
+            if let Some(old) = &old {
+                index.remove(key, old);
+            }
+            if let Some(new) = &new {
+                index.insert(key, new);
+            }
+        }
+    }
+
+    /// The store was reset, everything is about to be put back
+    pub(crate) fn clear(&self) {
+        for index in self.indexes.write().unwrap().iter_mut() {
+            index.entries.clear();
+        }
+    }
+
+    /// Calls `f` with each key under `prefix` that may match `cond`, in key order and
+    /// starting after `after`, until it returns false. Uses an index declared for the
+    /// condition's field over a prefix covering `prefix`, None if there isn't one.
+    pub(crate) fn candidates(
+        &self,
+        prefix: &[u8],
+        cond: &Condition,
+        after: Option<&[u8]>,
+        mut f: impl FnMut(&[u8]) -> bool,
+    ) -> Option<()> {
+        let indexes = self.indexes.read().unwrap();
+        let index = indexes
+            .iter()
+            .find(|index| prefix.starts_with(&index.prefix) && index.field == cond.field)?;
+        let range = match cond.op {
+            CmpOp::Eq => (Bound::Included(&cond.value), Bound::Included(&cond.value)),
+            CmpOp::Gt => (Bound::Excluded(&cond.value), Bound::Unbounded),
+            CmpOp::Ge => (Bound::Included(&cond.value), Bound::Unbounded),
+            CmpOp::Lt => (Bound::Unbounded, Bound::Excluded(&cond.value)),
+            CmpOp::Le => (Bound::Unbounded, Bound::Included(&cond.value)),
+            CmpOp::Ne => (Bound::Unbounded, Bound::Unbounded),
+        };
+        let prefix_end = prefix_end(prefix);
+        let lower = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        let upper = match &prefix_end {
+            Some(end) => Bound::Excluded(end.as_slice()),
+            None => Bound::Unbounded,
+        };
+        if let (Bound::Excluded(after), Bound::Excluded(end)) = (lower, upper) {
+            if after >= end {
+                return Some(());
+            }
+        }
+
+        // Each value keeps its keys in order, so merge them rather than collect them
+        let mut values: Vec<_> = index
+            .entries
+            .range::<Scalar, _>(range)
+            .filter(|(value, _)| match cond.op {
+                CmpOp::Eq => true,
+                CmpOp::Ne => **value != cond.value,
+                _ => value.same_type(&cond.value),
+            })
+            .map(|(_, keys)| keys.range::<[u8], _>((lower, upper)))
+            .collect();
+        let mut next: BinaryHeap<_> = values
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, keys)| Some(Reverse((keys.next()?, i))))
+            .collect();
+        while let Some(Reverse((key, i))) = next.pop() {
+            if let Some(after) = values[i].next() {
+                next.push(Reverse((after, i)));
+            }
+            if !f(key) {
+                break;
+            }
+        }
+        Some(())
+    }
+
+    /// Whether every field in `query` has an index over a prefix covering `prefix`
+    pub(crate) fn covers(&self, prefix: &[u8], query: &Query) -> Result<(), String> {
+        let indexes = self.indexes.read().unwrap();
+        for cond in &query.conditions {
+            if !indexes
+                .iter()
+                .any(|index| prefix.starts_with(&index.prefix) && index.field == cond.field)
+            {
+                return Err(cond.field.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Condition {
+    pub field: String,
+    pub op: CmpOp,
+    pub value: Scalar,
+}
+
+impl Condition {
+    fn matches(&self, doc: &Value) -> bool {
+        let Some(value) = lookup(doc, &self.field) else {
+            return false;
+        };
+        match self.op {
+            CmpOp::Eq => value == self.value,
+            CmpOp::Ne => value != self.value,
+            _ if !value.same_type(&self.value) => false,
+            CmpOp::Lt => value < self.value,
+            CmpOp::Le => value <= self.value,
+            CmpOp::Gt => value > self.value,
+            CmpOp::Ge => value >= self.value,
+        }
+    }
+}
+
+/// Conditions joined by AND, e.g. `status = "failed" AND attempts > 3`. Values are
+/// JSON literals.
+#[derive(Debug, PartialEq)]
+pub struct Query {
+    pub conditions: Vec<Condition>,
+}
+
+impl Query {
+    pub fn parse(input: &str) -> anyhow::Result<Self> {
+        let mut rest = input.trim();
+        let mut conditions = Vec::new();
+        loop {
+            let (cond, after) = parse_condition(rest)?;
+            conditions.push(cond);
+            rest = after.trim_start();
+            if rest.is_empty() {
+                break;
+            }
+            let Some(after) = rest
+                .get(..3)
+                .filter(|and| and.eq_ignore_ascii_case("AND"))
+                .and_then(|_| rest.get(3..))
+                .filter(|after| after.starts_with(char::is_whitespace))
+            else {
+                bail!("expected AND at '{}'", rest);
+            };
+            rest = after.trim_start();
+        }
+        Ok(Self { conditions })
+    }
+
+    /// The condition to look up in an index: equality narrows things down the most
+    pub(crate) fn driver(&self) -> &Condition {
+        self.conditions
+            .iter()
+            .find(|cond| cond.op == CmpOp::Eq)
+            .or_else(|| self.conditions.iter().find(|cond| cond.op != CmpOp::Ne))
+            .unwrap_or(&self.conditions[0])
+    }
+
+    pub(crate) fn matches(&self, item: &Item) -> bool {
+        parse_doc(item).is_some_and(|doc| self.conditions.iter().all(|cond| cond.matches(&doc)))
+    }
+}
+
+fn parse_condition(input: &str) -> anyhow::Result<(Condition, &str)> {
+    let field_len = input
+        .find(|c: char| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+        .unwrap_or(input.len());
+    if field_len == 0 {
+        bail!("expected a field name at '{}'", input);
+    }
+    let (field, rest) = input.split_at(field_len);
+    let rest = rest.trim_start();
+
+    let (op, rest) = [
+        ("==", CmpOp::Eq),
+        ("!=", CmpOp::Ne),
+        ("<=", CmpOp::Le),
+        (">=", CmpOp::Ge),
+        ("=", CmpOp::Eq),
+        ("<", CmpOp::Lt),
+        (">", CmpOp::Gt),
+    ]
+    .into_iter()
+    .find_map(|(token, op)| rest.strip_prefix(token).map(|rest| (op, rest)))
+    .ok_or_else(|| anyhow!("expected a comparison after '{}'", field))?;
+    let rest = rest.trim_start();
+
+    // Let serde_json find where the literal ends
+    let mut values = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+    let value = values
+        .next()
+        .ok_or_else(|| anyhow!("expected a value after '{}'", field))?
+        .map_err(|e| anyhow!("invalid value for '{}': {}", field, e))?;
+    let value = Scalar::from_json(&value).ok_or_else(|| {
+        anyhow!(
+            "'{}' has to be compared with a string, number, bool or null",
+            field
+        )
+    })?;
+    let rest = &rest[values.byte_offset()..];
+
+    Ok((
+        Condition {
+            field: field.to_string(),
+            op,
+            value,
+        },
+        rest,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(json: &str) -> Item {
+        Item {
+            version: 1,
+            data: json.as_bytes().to_vec(),
+            expires_at: None,
+            meta: Default::default(),
+        }
+    }
+
+    fn candidates(indexes: &Indexes, query: &Query, after: Option<&[u8]>) -> Vec<Vec<u8>> {
+        let mut found = Vec::new();
+        indexes
+            .candidates(b"jobs/", query.driver(), after, |key| {
+                found.push(key.to_vec());
+                true
+            })
+            .unwrap();
+        found
+    }
+
+    #[test]
+    fn indexes_and_queries_fields() {
+        let indexes = Indexes::new(
+            toml::from_str(
+                r#"
+                [[index]]
+                prefix = "jobs/"
+                fields = ["status", "attempts"]
+                "#,
+            )
+            .unwrap(),
+        );
+        let failed = item(r#"{"status": "failed", "attempts": 5}"#);
+        indexes.update(b"jobs/1", None, Some(&failed));
+        indexes.update(
+            b"jobs/2",
+            None,
+            Some(&item(r#"{"status": "failed", "attempts": 2}"#)),
+        );
+        indexes.update(
+            b"jobs/3",
+            None,
+            Some(&item(r#"{"status": "done", "attempts": "9"}"#)),
+        );
+        indexes.update(b"other/1", None, Some(&failed));
+
+        let query = Query::parse(r#"status = "failed" AND attempts > 3"#).unwrap();
+        assert_eq!(query.conditions.len(), 2);
+        assert!(indexes.covers(b"jobs/", &query).is_ok());
+        assert_eq!(indexes.covers(b"other/", &query), Err("status".to_string()));
+
+        let found = candidates(&indexes, &query, None);
+        assert_eq!(found, [b"jobs/1".to_vec(), b"jobs/2".to_vec()]);
+        assert_eq!(
+            candidates(&indexes, &query, Some(b"jobs/1")),
+            [b"jobs/2".to_vec()]
+        );
+        assert!(candidates(&indexes, &query, Some(b"jobs0")).is_empty());
+        assert!(query.matches(&failed));
+        assert!(!query.matches(&item(r#"{"status": "failed", "attempts": 2}"#)));
+
+        // A string isn't greater than a number
+        let query = Query::parse("attempts>=3").unwrap();
+        assert_eq!(candidates(&indexes, &query, None), [b"jobs/1".to_vec()]);
+        // Keys come out in order whatever their values
+        let query = Query::parse("attempts>=1").unwrap();
+        let found = candidates(&indexes, &query, Some(b"a"));
+        assert_eq!(found, [b"jobs/1".to_vec(), b"jobs/2".to_vec()]);
+
+        indexes.update(b"jobs/1", Some(&failed), None);
+        let query = Query::parse(r#"status == "failed""#).unwrap();
+        assert_eq!(candidates(&indexes, &query, None), [b"jobs/2".to_vec()]);
+
+        assert!(Query::parse("status = failed").is_err());
+        assert!(Query::parse(r#"status = "a" OR b = 1"#).is_err());
+        assert!(Query::parse("a = [1]").is_err());
+    }
+}
@@ -1,44 +1,315 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    future::{Future, IntoFuture},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
+use auth::AuthConfig;
 use axum::{
     extract::DefaultBodyLimit,
     http::StatusCode,
+    middleware,
     response::{IntoResponse, Response},
-    routing::get,
+    routing::{get, post, put},
 };
-use tokio::sync::RwLock;
-use tracing::info;
+use index::{IndexConfig, Indexes};
+use quota::{QuotaConfig, Quotas};
+use replication::Leader;
+use serde::{Deserialize, Serialize};
+use storage::{Op, Sharded, Storage, MAX_PARTITION_KEYS};
+use tracing::{debug, error, info};
+use upload::Uploads;
+use watch::Watcher;
 
+pub mod auth;
+pub mod backup;
+pub mod client;
+pub mod config;
+pub mod index;
+mod metrics;
+pub mod quota;
+pub mod replication;
+mod resp;
 mod routes;
+pub mod storage;
+mod upload;
+mod watch;
+
+// How often we ask the storage to fold its log into a snapshot
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+// How often we sweep expired keys out of the storage
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+// Any more are left for the next tick
+const MAX_REAP_KEYS: usize = 10_000;
 
 #[derive(Clone, Debug)]
 struct AppState {
-    kv: Arc<RwLock<BTreeMap<String, Item>>>,
+    kv: Kv,
+    watcher: Arc<Watcher>,
+    // None means everything is open
+    auth: Option<Arc<AuthConfig>>,
+    // Set when we're a read-only follower
+    leader: Option<Arc<Leader>>,
+    uploads: Arc<Uploads>,
 }
 
-#[derive(Clone, Debug)]
-struct Item {
-    version: i64,
-    data: Vec<u8>,
+type Kv = Arc<Sharded>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Item {
+    pub version: i64,
+    pub data: Vec<u8>,
+    /// Unix millis after which the item is treated as gone
+    pub expires_at: Option<i64>,
+    pub meta: Meta,
 }
 
-pub async fn start(addr: &str) {
+/// What the writer told us about the value, handed back with it on reads
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Meta {
+    pub content_type: Option<String>,
+    /// From the `x-meta-*` headers, by the rest of the header name
+    pub fields: BTreeMap<String, String>,
+}
+
+impl Item {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_millis())
+    }
+}
+
+/// How to run the server, everything but the storage
+#[derive(Debug)]
+pub struct Options {
+    pub addr: String,
+    /// Also serve the store over the Redis protocol here
+    pub resp_addr: Option<String>,
+    pub auth: Option<AuthConfig>,
+    /// Makes this a read-only follower of another httpkv
+    pub leader: Option<Leader>,
+    pub indexes: Option<IndexConfig>,
+    pub quotas: Option<QuotaConfig>,
+    /// Largest request body, except for upload chunks
+    pub body_limit: usize,
+    /// How long to wait for in-flight requests once `shutdown` resolves
+    pub drain_timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            addr: config::DEFAULT_LISTEN.to_string(),
+            resp_addr: None,
+            auth: None,
+            leader: None,
+            indexes: None,
+            quotas: None,
+            body_limit: config::DEFAULT_BODY_LIMIT,
+            drain_timeout: config::DEFAULT_DRAIN_TIMEOUT,
+        }
+    }
+}
+
+/// Serves until `shutdown` resolves, then stops taking connections, gives requests in
+/// flight up to `drain_timeout` to finish and checkpoints the storage.
+pub async fn start(
+    options: Options,
+    storage: Box<dyn Storage>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) {
+    let Options {
+        addr,
+        resp_addr,
+        auth,
+        leader,
+        indexes,
+        quotas,
+        body_limit,
+        drain_timeout,
+    } = options;
+    let mut kv = Sharded::new(storage, MAX_PARTITION_KEYS);
+    if let Some(indexes) = indexes {
+        kv = kv.indexed(Arc::new(Indexes::new(indexes)));
+    }
+    if let Some(quotas) = quotas {
+        kv = kv.limited(Arc::new(Quotas::new(quotas)));
+    }
+    let watcher = Arc::new(Watcher::new(kv.revision()));
     let state = AppState {
-        kv: Arc::new(RwLock::new(BTreeMap::new())),
+        kv: Arc::new(kv.watched(watcher.clone())),
+        watcher,
+        auth: auth.map(Arc::new),
+        leader: leader.clone().map(Arc::new),
+        uploads: Arc::new(Uploads::default()),
+    };
+
+    let kv = state.kv.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECKPOINT_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = kv.checkpoint().await {
+                error!("Failed to checkpoint storage: {:?}", e);
+            }
+        }
+    });
+
+    let kv = state.kv.clone();
+    match leader {
+        // The leader's reaper deletes come through with everything else
+        Some(leader) => {
+            tokio::spawn(replication::follow(kv, leader));
+        }
+        None => {
+            let uploads = state.uploads.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(REAP_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = reap_expired(&kv).await {
+                        error!("Failed to reap expired keys: {:?}", e);
+                    }
+                    uploads.reap();
+                }
+            });
+        }
+    }
+
+    let (draining_tx, draining_rx) = tokio::sync::watch::channel(false);
+    let resp = match resp_addr {
+        Some(resp_addr) => Some(
+            resp::start(&resp_addr, state.clone(), body_limit, draining_rx.clone())
+                .await
+                .expect("Failed to start the RESP listener"),
+        ),
+        None => None,
     };
+
     let app = axum::Router::new()
         .route("/", get(routes::get::get_root))
+        .route("/_metrics", get(metrics::metrics))
+        .route("/_txn", post(routes::txn::txn))
+        .route("/_mget", post(routes::batch::multi_get))
+        .route("/_mput", post(routes::batch::multi_put))
+        .route("/_query", get(routes::query::query))
+        .route("/_quotas", get(routes::quota::usage))
+        .route("/_compact", post(routes::post::compact))
+        .route("/_backup", get(routes::backup::backup))
+        .route(
+            "/_restore",
+            post(routes::backup::restore).layer(DefaultBodyLimit::max(backup::MAX_RESTORE_BYTES)),
+        )
+        .route("/_changes", get(routes::changes::changes))
+        .route("/_uploads", post(routes::upload::start_upload))
+        .route(
+            "/_uploads/:id",
+            get(routes::upload::upload_status)
+                .post(routes::upload::commit_upload)
+                .delete(routes::upload::abort_upload),
+        )
+        .route(
+            "/_uploads/:id/:n",
+            put(routes::upload::put_chunk).layer(DefaultBodyLimit::max(upload::MAX_CHUNK_BYTES)),
+        )
         .route(
             "/:key",
-            get(routes::get::get_key).post(routes::post::write_key),
+            get(routes::get::get_key)
+                .head(routes::get::head_key)
+                .post(routes::post::write_key)
+                .patch(routes::patch::patch_key)
+                .delete(routes::delete::delete_key),
         )
-        .with_state(state)
-        .layer(DefaultBodyLimit::max(99_000));
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authorize,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            replication::redirect_writes,
+        ))
+        .route_layer(middleware::from_fn(metrics::track))
+        .with_state(state.clone())
+        .layer(DefaultBodyLimit::max(body_limit));
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
 
     info!("Starting on {}", addr);
-    axum::serve(listener, app).await.unwrap();
+    let serve = axum::serve(listener, app).with_graceful_shutdown(async move {
+        shutdown.await;
+        info!("Shutting down, waiting for requests in flight");
+        let _ = draining_tx.send(true);
+    });
+    // Both listeners stop taking connections together, and share the one deadline
+    let serve = async move {
+        let served = serve.into_future().await;
+        if let Some(resp) = resp {
+            resp.await.unwrap();
+        }
+        served
+    };
+    // Watches and change feeds never finish on their own, so don't wait forever
+    let mut draining = draining_rx;
+    let deadline = async move {
+        if draining.wait_for(|draining| *draining).await.is_ok() {
+            tokio::time::sleep(drain_timeout).await;
+        } else {
+            std::future::pending().await
+        }
+    };
+    tokio::select! {
+        result = serve => result.unwrap(),
+        () = deadline => info!("Gave up waiting after {:?}", drain_timeout),
+    }
+
+    if let Err(e) = state.kv.checkpoint().await {
+        error!("Failed to checkpoint storage: {:?}", e);
+    }
+    info!("Shut down");
+}
+
+async fn reap_expired(kv: &Kv) -> anyhow::Result<()> {
+    // Only lock what's due, and not so much of it that writers wait long
+    let expired = kv.expired(now_millis(), MAX_REAP_KEYS);
+    if expired.is_empty() {
+        return Ok(());
+    }
+
+    let mut kv = kv.write(&expired).await;
+    let version = kv.next_version();
+    // Something may have rewritten them in between
+    let ops: Vec<Op> = expired
+        .into_iter()
+        .filter(|key| kv.get(key).is_some_and(|item| item.is_expired()))
+        .map(|key| Op::Delete { key, version })
+        .collect();
+    debug!(count = ops.len(), "reaping expired keys");
+    kv.apply(ops)
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+/// When something written at `from` with a `ttl` in seconds expires, in unix millis
+fn expiry_after(from: i64, ttl: u64) -> Result<i64, AppError> {
+    i64::try_from(ttl)
+        .ok()
+        .and_then(|ttl| ttl.checked_mul(1000))
+        .and_then(|ttl| from.checked_add(ttl))
+        .ok_or_else(|| {
+            AppError::CustomCode(anyhow::anyhow!("ttl is too long"), StatusCode::BAD_REQUEST)
+        })
+}
+
+/// `expires_at` in unix seconds, as the unix millis we store
+fn expiry_at(at: i64) -> Result<i64, AppError> {
+    at.checked_mul(1000).ok_or_else(|| {
+        AppError::CustomCode(anyhow::anyhow!("expires_at is out of range"), StatusCode::BAD_REQUEST)
+    })
 }
 
 // Make our own error that wraps `anyhow::Error`.
@@ -1,21 +1,84 @@
-use httpkv::start;
-use tracing::{level_filters, Level};
+use httpkv::{
+    auth::AuthConfig,
+    config::{Config, LogFormat},
+    index::IndexConfig,
+    quota::QuotaConfig,
+    replication::Leader,
+    start,
+    storage::{DiskStorage, MemoryStorage, Storage},
+    Options,
+};
+use tracing::level_filters;
 use tracing_subscriber::{fmt::format::FmtSpan, layer::SubscriberExt, Layer};
 
 #[tokio::main]
 async fn main() {
+    // See config.rs for every setting and where it can come from
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Failed to load config: {:?}", e);
+        std::process::exit(1)
+    });
+
     // tracing_subscriber::fmt::init();
-    let subscriber = tracing_subscriber::registry().with(
-        tracing_subscriber::fmt::layer()
-            .compact()
-            .with_file(true)
-            .with_line_number(true)
-            .with_span_events(FmtSpan::CLOSE)
-            .with_target(false)
-            // .json()
-            .with_filter(level_filters::LevelFilter::from_level(Level::DEBUG))
-    );
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_file(true)
+        .with_line_number(true)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_target(false);
+    let fmt = match config.log_format {
+        LogFormat::Compact => fmt.compact().boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(fmt.with_filter(level_filters::LevelFilter::from_level(config.log_level)));
 
     tracing::subscriber::set_global_default(subscriber).unwrap();
-    start("0.0.0.0:8080").await
+
+    let storage: Box<dyn Storage> = match config.data_dir {
+        Some(dir) => Box::new(DiskStorage::open(dir).expect("Failed to open disk storage")),
+        None => Box::new(MemoryStorage::new()),
+    };
+    let options = Options {
+        addr: config.listen,
+        resp_addr: config.resp_listen,
+        auth: config
+            .auth_file
+            .map(|path| AuthConfig::load(path).expect("Failed to load auth file")),
+        leader: config.leader_url.map(|url| Leader {
+            url,
+            token: config.leader_token,
+        }),
+        indexes: config
+            .index_file
+            .map(|path| IndexConfig::load(path).expect("Failed to load index file")),
+        quotas: config
+            .quota_file
+            .map(|path| QuotaConfig::load(path).expect("Failed to load quota file")),
+        body_limit: config.body_limit,
+        drain_timeout: config.drain_timeout,
+    };
+    start(options, storage, shutdown_signal()).await
+}
+
+/// Ctrl-C, or SIGTERM from whatever is supervising us
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl-C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
+    }
 }
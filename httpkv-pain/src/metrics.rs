@@ -0,0 +1,108 @@
+use std::{ops::Bound, sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, Encoder, HistogramVec,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+
+use crate::{AppError, AppState};
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "httpkv_requests_total",
+        "Requests handled, by route, method and status code",
+        &["route", "method", "status"]
+    )
+    .unwrap()
+});
+
+static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "httpkv_request_duration_seconds",
+        "Time to produce a response (not to finish streaming it), by route and method",
+        &["route", "method"]
+    )
+    .unwrap()
+});
+
+static LOCK_WAIT: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "httpkv_lock_wait_seconds",
+        "Time spent waiting to lock the partitions a request needs, by read or write",
+        &["mode"],
+        // Mostly uncontended, so start well under the default buckets
+        vec![0.00001, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]
+    )
+    .unwrap()
+});
+
+static KEYS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "httpkv_keys",
+        "Keys in the store, including expired ones not yet reaped"
+    )
+    .unwrap()
+});
+
+static STORED_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("httpkv_stored_bytes", "Total size of live keys and values").unwrap()
+});
+
+static PARTITIONS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "httpkv_partitions",
+        "Key range partitions the store is split into"
+    )
+    .unwrap()
+});
+
+pub fn observe_lock_wait(mode: &str, started: Instant) {
+    LOCK_WAIT
+        .with_label_values(&[mode])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+/// Counts and times every request that matched a route
+pub(crate) async fn track(matched: MatchedPath, req: Request, next: Next) -> Response {
+    let route = matched.as_str().to_owned();
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    let res = next.run(req).await;
+
+    REQUEST_DURATION
+        .with_label_values(&[&route, &method])
+        .observe(started.elapsed().as_secs_f64());
+    REQUESTS
+        .with_label_values(&[&route, &method, res.status().as_str()])
+        .inc();
+    res
+}
+
+/// Everything in the Prometheus text format
+pub(crate) async fn metrics(State(state): State<AppState>) -> Result<Response, AppError> {
+    {
+        let kv = state
+            .kv
+            .read_range((Bound::Unbounded, Bound::Unbounded))
+            .await;
+        KEYS.set(kv.len() as i64);
+        STORED_BYTES.set(kv.stored_bytes() as i64);
+        PARTITIONS.set(state.kv.partition_count() as i64);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut body)?;
+    Ok((
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        body,
+    )
+        .into_response())
+}
@@ -0,0 +1,377 @@
+//! Per-prefix limits on how much a tenant can store and how fast it can write.
+//!
+//! Usage is counted by [`Sharded`](crate::storage::Sharded) as it applies each batch,
+//! so it covers every way a key can change. The limits are checked by the routes that
+//! write, a key at a time, against usage from before the batch plus the batch's
+//! earlier writes. Concurrent requests to different partitions under one prefix can
+//! each race past a limit by a batch.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context};
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{storage::entry_size, AppError, Item};
+
+/// Limits loaded from a TOML file like:
+///
+/// ```toml
+/// [[quota]]
+/// prefix = "tenant-a/"
+/// max_bytes = 104857600
+/// max_keys = 100000
+/// writes_per_sec = 50
+/// ```
+///
+/// Every limit is optional. A key under more than one prefix has to fit all of them.
+#[derive(Deserialize, Debug, Default)]
+pub struct QuotaConfig {
+    #[serde(default, rename = "quota")]
+    quotas: Vec<Limits>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct Limits {
+    prefix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_keys: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    writes_per_sec: Option<u32>,
+}
+
+impl QuotaConfig {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let raw =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        toml::from_str(&raw).with_context(|| format!("parsing {}", path.display()))
+    }
+}
+
+#[derive(Debug)]
+struct Quota {
+    limits: Limits,
+    // Including expired keys that haven't been reaped, they still take up the space
+    keys: AtomicI64,
+    bytes: AtomicI64,
+    bucket: Mutex<Bucket>,
+}
+
+/// Refills at `writes_per_sec`, holding up to a second's worth
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Quota {
+    fn covers(&self, key: &[u8]) -> bool {
+        key.starts_with(self.limits.prefix.as_bytes())
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: f64, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled = now;
+    }
+}
+
+/// What the admin endpoint reports for each prefix
+#[derive(Serialize, Debug)]
+pub struct Usage {
+    #[serde(flatten)]
+    limits: Limits,
+    keys: i64,
+    bytes: i64,
+}
+
+#[derive(Debug, Default)]
+pub struct Quotas {
+    quotas: Vec<Quota>,
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Self {
+        Self {
+            quotas: config
+                .quotas
+                .into_iter()
+                .map(|limits| Quota {
+                    bucket: Mutex::new(Bucket {
+                        tokens: limits.writes_per_sec.unwrap_or(0) as f64,
+                        refilled: Instant::now(),
+                    }),
+                    limits,
+                    keys: AtomicI64::new(0),
+                    bytes: AtomicI64::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    /// `key` is changing from `old` to `new`, either of which can be None
+    pub(crate) fn update(&self, key: &[u8], old: Option<&Item>, new: Option<&Item>) {
+        let size = |item: Option<&Item>| item.map_or(0, |item| entry_size(key, item) as i64);
+        let keys = new.is_some() as i64 - old.is_some() as i64;
+        let bytes = size(new) - size(old);
+        for quota in self.quotas.iter().filter(|quota| quota.covers(key)) {
+            quota.keys.fetch_add(keys, Ordering::Relaxed);
+            quota.bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// The store was reset, everything is about to be put back
+    pub(crate) fn clear(&self) {
+        for quota in &self.quotas {
+            quota.keys.store(0, Ordering::Relaxed);
+            quota.bytes.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Checks a write on its own, see [`Staged::check_write`]
+    pub(crate) fn check_write(
+        &self,
+        key: &[u8],
+        old: Option<&Item>,
+        len: usize,
+    ) -> Result<(), AppError> {
+        self.batch().check_write(key, old, len)
+    }
+
+    /// For checking the writes of one batch, each counting the ones before it
+    pub(crate) fn batch(&self) -> Staged<'_> {
+        Staged {
+            quotas: self,
+            deltas: vec![(0, 0); self.quotas.len()],
+        }
+    }
+
+    pub fn usage(&self) -> Vec<Usage> {
+        self.quotas
+            .iter()
+            .map(|quota| Usage {
+                limits: quota.limits.clone(),
+                keys: quota.keys.load(Ordering::Relaxed),
+                bytes: quota.bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// The writes checked so far in a batch that hasn't been applied yet
+pub(crate) struct Staged<'a> {
+    quotas: &'a Quotas,
+    // How many keys and bytes the batch adds to each quota, in the same order
+    deltas: Vec<(i64, i64)>,
+}
+
+impl Staged<'_> {
+    /// Indexes of the quotas `key` is under
+    fn covering(&self, key: &[u8]) -> Vec<usize> {
+        (0..self.quotas.quotas.len())
+            .filter(|&i| self.quotas.quotas[i].covers(key))
+            .collect()
+    }
+
+    /// Whether `key` can go from `old` to a value of `len` bytes, where `old` is what
+    /// the batch has for it so far. A write that doesn't fit is a 507, one over the
+    /// rate limit a 429. Only a write that passes every quota uses up any rate.
+    pub(crate) fn check_write(
+        &mut self,
+        key: &[u8],
+        old: Option<&Item>,
+        len: usize,
+    ) -> Result<(), AppError> {
+        let new_keys = old.is_none() as i64;
+        let new_bytes =
+            (key.len() + len) as i64 - old.map_or(0, |item| entry_size(key, item) as i64);
+        let covering = self.covering(key);
+        for &i in &covering {
+            let quota = &self.quotas.quotas[i];
+            let (staged_keys, staged_bytes) = self.deltas[i];
+            let keys = quota.keys.load(Ordering::Relaxed) + staged_keys;
+            if let Some(max) = quota.limits.max_keys {
+                if new_keys > 0 && keys + new_keys > max as i64 {
+                    return Err(AppError::CustomCode(
+                        anyhow!(
+                            "Prefix {:?} is at its limit of {} keys",
+                            quota.limits.prefix,
+                            max
+                        ),
+                        StatusCode::INSUFFICIENT_STORAGE,
+                    ));
+                }
+            }
+            let bytes = quota.bytes.load(Ordering::Relaxed) + staged_bytes;
+            if let Some(max) = quota.limits.max_bytes {
+                if new_bytes > 0 && bytes + new_bytes > max as i64 {
+                    return Err(AppError::CustomCode(
+                        anyhow!(
+                            "Prefix {:?} would hold {} bytes, over its limit of {}",
+                            quota.limits.prefix,
+                            bytes + new_bytes,
+                            max
+                        ),
+                        StatusCode::INSUFFICIENT_STORAGE,
+                    ));
+                }
+            }
+        }
+
+        // Every bucket at once, so none is drawn from unless they all have a token
+        let now = Instant::now();
+        let mut buckets = Vec::new();
+        for &i in &covering {
+            let quota = &self.quotas.quotas[i];
+            let Some(rate) = quota.limits.writes_per_sec else {
+                continue;
+            };
+            let mut bucket = quota.bucket.lock().unwrap();
+            bucket.refill(rate as f64, now);
+            if bucket.tokens < 1.0 {
+                return Err(AppError::CustomCode(
+                    anyhow!(
+                        "Prefix {:?} is limited to {} writes per second",
+                        quota.limits.prefix,
+                        rate
+                    ),
+                    StatusCode::TOO_MANY_REQUESTS,
+                ));
+            }
+            buckets.push(bucket);
+        }
+        for mut bucket in buckets {
+            bucket.tokens -= 1.0;
+        }
+
+        for i in covering {
+            self.deltas[i].0 += new_keys;
+            self.deltas[i].1 += new_bytes;
+        }
+        Ok(())
+    }
+
+    /// `key` is being deleted by the batch, freeing up what `old` took
+    pub(crate) fn delete(&mut self, key: &[u8], old: Option<&Item>) {
+        let Some(old) = old else {
+            return;
+        };
+        for i in self.covering(key) {
+            self.deltas[i].0 -= 1;
+            self.deltas[i].1 -= entry_size(key, old) as i64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(data: &str) -> Item {
+        Item {
+            version: 1,
+            data: data.as_bytes().to_vec(),
+            expires_at: None,
+            meta: Default::default(),
+        }
+    }
+
+    fn status(result: Result<(), AppError>) -> Option<StatusCode> {
+        match result {
+            Ok(()) => None,
+            Err(AppError::CustomCode(_, status)) => Some(status),
+            Err(AppError::Anyhow(e)) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let quotas = Quotas::new(
+            toml::from_str(
+                r#"
+                [[quota]]
+                prefix = "a/"
+                max_keys = 2
+                max_bytes = 20
+                [[quota]]
+                prefix = "b/"
+                writes_per_sec = 2
+                "#,
+            )
+            .unwrap(),
+        );
+        quotas.update(b"a/1", None, Some(&item("12345")));
+        quotas.update(b"a/2", None, Some(&item("12345")));
+        assert_eq!(quotas.usage()[0].keys, 2);
+        assert_eq!(quotas.usage()[0].bytes, 16);
+
+        let full = Some(StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(status(quotas.check_write(b"a/3", None, 1)), full);
+        assert_eq!(
+            status(quotas.check_write(b"a/1", Some(&item("12345")), 10)),
+            full
+        );
+        // Shrinking or staying the same size is always fine
+        assert_eq!(
+            status(quotas.check_write(b"a/1", Some(&item("12345")), 5)),
+            None
+        );
+        quotas.update(b"a/1", Some(&item("12345")), None);
+        assert_eq!(status(quotas.check_write(b"a/3", None, 1)), None);
+
+        assert_eq!(status(quotas.check_write(b"b/1", None, 1)), None);
+        assert_eq!(status(quotas.check_write(b"b/1", None, 1)), None);
+        assert_eq!(
+            status(quotas.check_write(b"b/1", None, 1)),
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(status(quotas.check_write(b"c/1", None, 1)), None);
+    }
+
+    #[test]
+    fn counts_the_whole_batch() {
+        let quotas = Quotas::new(
+            toml::from_str(
+                r#"
+                [[quota]]
+                prefix = "a/"
+                max_keys = 2
+                [[quota]]
+                prefix = "b/"
+                writes_per_sec = 2
+                [[quota]]
+                prefix = "b/x"
+                writes_per_sec = 1
+                "#,
+            )
+            .unwrap(),
+        );
+        quotas.update(b"a/1", None, Some(&item("1")));
+        let mut batch = quotas.batch();
+        assert_eq!(status(batch.check_write(b"a/2", None, 1)), None);
+        let full = Some(StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(status(batch.check_write(b"a/3", None, 1)), full);
+        // Unless the batch makes room
+        batch.delete(b"a/1", Some(&item("1")));
+        assert_eq!(status(batch.check_write(b"a/3", None, 1)), None);
+
+        // b/x turning a write away doesn't use up b/'s rate
+        let limited = Some(StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(status(quotas.check_write(b"b/x1", None, 1)), None);
+        assert_eq!(status(quotas.check_write(b"b/x2", None, 1)), limited);
+        assert_eq!(status(quotas.check_write(b"b/y", None, 1)), None);
+        assert_eq!(status(quotas.check_write(b"b/z", None, 1)), limited);
+    }
+}
@@ -0,0 +1,175 @@
+//! Asynchronous leader/follower replication.
+//!
+//! A follower tails the leader's `/_changes` log from its own revision and applies each
+//! batch as-is, so keys keep the leader's versions and a follower's revision is how far
+//! it has got. If the leader no longer has history back that far (or the follower is
+//! somehow ahead) it loads a full `/_backup` behind an [`Op::Reset`] and carries on
+//! from there. Writes sent to a follower are redirected to the leader.
+
+use std::{ops::Bound, time::Duration};
+
+use anyhow::{anyhow, bail};
+use axum::{
+    extract::{Request, State},
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use tracing::{debug, info, warn};
+
+use crate::{
+    backup,
+    routes::changes::FORMAT_HEADER,
+    storage::{decode_record, Format, Op},
+    AppState, Kv,
+};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where a follower replicates from
+#[derive(Debug, Clone)]
+pub struct Leader {
+    /// e.g. `http://leader:8080`
+    pub url: String,
+    /// Needs admin if the leader has auth on
+    pub token: Option<String>,
+}
+
+/// Sends anything that isn't a read to the leader. 307 so the client repeats the same
+/// method and body there. Compaction is the exception, each node compacts its own history.
+/// A multi-get is a POST but only reads, so we serve it.
+pub(crate) async fn redirect_writes(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(leader) = &state.leader else {
+        return next.run(req).await;
+    };
+    let method = req.method();
+    let local = matches!(req.uri().path(), "/_compact" | "/_mget");
+    if *method == Method::GET || *method == Method::HEAD || local {
+        return next.run(req).await;
+    }
+
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    let location = format!("{}{}", leader.url.trim_end_matches('/'), path);
+    (
+        StatusCode::TEMPORARY_REDIRECT,
+        [(header::LOCATION, location)],
+        "This is a read-only follower, writes go to the leader",
+    )
+        .into_response()
+}
+
+/// Runs forever, reconnecting with backoff whenever the stream breaks
+pub(crate) async fn follow(kv: Kv, leader: Leader) {
+    let client = reqwest::Client::new();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match tail(&client, &kv, &leader, &mut backoff).await {
+            Ok(()) => info!("Leader closed the change stream, reconnecting"),
+            Err(e) => warn!(
+                backoff_ms = backoff.as_millis() as u64,
+                "Replication from {} failed: {:?}", leader.url, e
+            ),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Applies the change stream until it ends. Resets `backoff` once we're connected.
+async fn tail(
+    client: &reqwest::Client,
+    kv: &Kv,
+    leader: &Leader,
+    backoff: &mut Duration,
+) -> anyhow::Result<()> {
+    let from = kv.revision();
+    let res = request(client, leader, &format!("/_changes?from={}&format=2", from))
+        .send()
+        .await?;
+    match res.status() {
+        reqwest::StatusCode::OK => {}
+        reqwest::StatusCode::GONE | reqwest::StatusCode::CONFLICT => {
+            info!(
+                from = from,
+                "Leader can't resume us from here, loading a full backup"
+            );
+            resync(client, kv, leader).await?;
+            *backoff = MIN_BACKOFF;
+            return Ok(());
+        }
+        status => bail!("leader returned {}: {}", status, res.text().await?),
+    }
+    // A leader from before meta ignores `format` and doesn't set the header
+    let format = match res.headers().get(FORMAT_HEADER) {
+        Some(value) if value == "2" => Format::V2,
+        _ => Format::V1,
+    };
+    info!(from = from, format = ?format, "Following {}", leader.url);
+    *backoff = MIN_BACKOFF;
+
+    let mut body = res.bytes_stream();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.next().await {
+        buf.extend_from_slice(&chunk?);
+        let mut offset = 0;
+        while let Some((ops, len)) = decode_record(&buf[offset..], format)? {
+            offset += len;
+            let count = ops.len();
+            kv.write_range((Bound::Unbounded, Bound::Unbounded))
+                .await
+                .apply(ops)?;
+            debug!(ops = count, "applied batch from leader");
+        }
+        buf.drain(..offset);
+    }
+    Ok(())
+}
+
+/// Replaces everything we have with a backup from the leader, in one batch
+async fn resync(client: &reqwest::Client, kv: &Kv, leader: &Leader) -> anyhow::Result<()> {
+    let res = request(client, leader, "/_backup").send().await?;
+    if !res.status().is_success() {
+        bail!(
+            "leader returned {} for backup: {}",
+            res.status(),
+            res.text().await?
+        );
+    }
+    let backup = backup::decode(&res.bytes().await?)
+        .map_err(|e| anyhow!("invalid backup from leader: {}", e))?;
+
+    let mut ops = Vec::with_capacity(backup.entries.len() + 1);
+    ops.push(Op::Reset {
+        revision: backup.revision,
+    });
+    let count = backup.entries.len();
+    ops.extend(
+        backup
+            .entries
+            .into_iter()
+            .map(|(key, item)| Op::Put { key, item }),
+    );
+    kv.write_range((Bound::Unbounded, Bound::Unbounded))
+        .await
+        .apply(ops)?;
+    info!(
+        revision = backup.revision,
+        keys = count,
+        "Loaded backup from leader"
+    );
+    Ok(())
+}
+
+fn request(client: &reqwest::Client, leader: &Leader, path: &str) -> reqwest::RequestBuilder {
+    let req = client.get(format!("{}{}", leader.url.trim_end_matches('/'), path));
+    match &leader.token {
+        Some(token) => req.bearer_auth(token),
+        None => req,
+    }
+}
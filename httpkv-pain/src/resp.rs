@@ -0,0 +1,702 @@
+//! A second listener that speaks RESP (the Redis protocol) against the same store, for
+//! tools that only know how to talk to Redis. It covers:
+//!
+//! ```text
+//! GET key
+//! SET key value [NX | XX] [EX seconds | PX millis]
+//! DEL key [key ...]
+//! SCAN cursor [MATCH pattern] [COUNT n]
+//! HTTPKV.VERSION [key]          the key's version, or the store's revision without one
+//! AUTH [username] token         with auth configured, before anything else
+//! PING, ECHO, SELECT 0, CLIENT, COMMAND, QUIT
+//! ```
+//!
+//! Keys and values are bulk strings, so they're bytes as is. SCAN cursors are the last
+//! key looked at, URL-safe base64 like the HTTP list cursor, with "0" for the start and
+//! the end.
+
+use std::{collections::BTreeSet, ops::Bound, sync::Arc};
+
+use anyhow::anyhow;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::{Grants, Permission},
+    routes::show_key,
+    storage::{prefix_end, Op},
+    AppError, AppState, Item, Meta,
+};
+
+const MAX_INLINE_BYTES: usize = 64 << 10;
+const DEFAULT_SCAN_COUNT: usize = 10;
+// A bigger COUNT is taken as this, a page holds the read lock while it walks its keys
+const MAX_SCAN_COUNT: usize = 1000;
+const MAX_ARGS: usize = 1 << 20;
+
+/// How big a command can be
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    args: usize,
+    bulk_bytes: usize,
+}
+
+// Until a connection has authenticated, when auth is configured. The same as Redis.
+const UNAUTHENTICATED_LIMITS: Limits = Limits {
+    args: 10,
+    bulk_bytes: 16 << 10,
+};
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn err(msg: impl std::fmt::Display) -> Self {
+        Self::Error(format!("ERR {}", msg))
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Self::Simple(s) => buf.extend(format!("+{}\r\n", s).as_bytes()),
+            // No newlines allowed in an error line
+            Self::Error(e) => {
+                buf.extend(format!("-{}\r\n", e.replace(['\r', '\n'], " ")).as_bytes())
+            }
+            Self::Integer(n) => buf.extend(format!(":{}\r\n", n).as_bytes()),
+            Self::Bulk(data) => {
+                buf.extend(format!("${}\r\n", data.len()).as_bytes());
+                buf.extend(data);
+                buf.extend(b"\r\n");
+            }
+            Self::Nil => buf.extend(b"$-1\r\n"),
+            Self::Array(items) => {
+                buf.extend(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(buf);
+                }
+            }
+        }
+    }
+}
+
+/// Starts the RESP listener next to the HTTP one, taking values up to the same
+/// `body_limit`. The task it returns finishes once `draining` turns true and every
+/// connection has finished what it was running.
+pub(crate) async fn start(
+    addr: &str,
+    state: AppState,
+    body_limit: usize,
+    draining: watch::Receiver<bool>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Speaking RESP on {}", addr);
+    let limits = Limits {
+        args: MAX_ARGS,
+        bulk_bytes: body_limit,
+    };
+    Ok(tokio::spawn(serve(listener, state, limits, draining)))
+}
+
+/// Accepts RESP connections until `draining` turns true, then waits on the ones open
+async fn serve(
+    listener: TcpListener,
+    state: AppState,
+    limits: Limits,
+    mut draining: watch::Receiver<bool>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = draining.wait_for(|draining| *draining) => break,
+        };
+        match accepted {
+            Ok((stream, peer)) => {
+                let state = state.clone();
+                let draining = draining.clone();
+                connections.spawn(async move {
+                    if let Err(e) = connection(stream, state, limits, draining).await {
+                        debug!(peer = %peer, "RESP connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept RESP connection: {}", e),
+        }
+        while connections.try_join_next().is_some() {}
+    }
+    drop(listener);
+    debug!(count = connections.len(), "waiting on RESP connections");
+    while connections.join_next().await.is_some() {}
+}
+
+/// Replies are buffered until we've run out of pipelined commands to answer. Once
+/// `draining`, hangs up instead of waiting on the next command.
+async fn connection(
+    stream: TcpStream,
+    state: AppState,
+    authenticated_limits: Limits,
+    mut draining: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let (read, write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut write = BufWriter::new(write);
+    let mut session = Session {
+        state,
+        grants: None,
+    };
+    let mut out = Vec::new();
+    loop {
+        let limits = match session.state.auth.is_some() && session.grants.is_none() {
+            true => UNAUTHENTICATED_LIMITS,
+            false => authenticated_limits,
+        };
+        let command = tokio::select! {
+            command = read_command(&mut read, limits) => command,
+            _ = draining.wait_for(|draining| *draining) => return Ok(()),
+        };
+        let args = match command {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                // We can't tell where the next command starts, so hang up
+                Reply::Error(format!("ERR Protocol error: {}", e)).encode(&mut out);
+                write.write_all(&out).await?;
+                write.flush().await?;
+                return Err(e);
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        session.run(args).await.encode(&mut out);
+        write.write_all(&out).await?;
+        out.clear();
+        if quit {
+            write.flush().await?;
+            return Ok(());
+        }
+        if read.buffer().is_empty() {
+            write.flush().await?;
+        }
+    }
+}
+
+/// One command's arguments, or None when the client hung up between commands. Takes
+/// both the array of bulk strings clients send and the inline form people type.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    read: &mut R,
+    limits: Limits,
+) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(read, MAX_INLINE_BYTES).await? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        return Ok(Some(
+            line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect(),
+        ));
+    };
+    let count = parse_len(count, limits.args)?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let line = read_line(read, MAX_INLINE_BYTES)
+            .await?
+            .ok_or_else(|| anyhow!("connection closed mid command"))?;
+        let len = line
+            .strip_prefix(b"$")
+            .ok_or_else(|| anyhow!("expected '$', got '{}'", show_key(&line)))?;
+        let len = parse_len(len, limits.bulk_bytes)?;
+        // Only as much as actually arrives, not whatever length the client claims
+        let mut arg = Vec::with_capacity((len + 2).min(MAX_INLINE_BYTES));
+        (&mut *read)
+            .take(len as u64 + 2)
+            .read_to_end(&mut arg)
+            .await?;
+        if arg.len() < len + 2 {
+            return Err(anyhow!("connection closed mid command"));
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(anyhow!("bulk string longer than its length"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// A line without its "\r\n", or None at a clean end of stream
+async fn read_line<R: AsyncBufRead + Unpin>(
+    read: &mut R,
+    max: usize,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let n = (&mut *read)
+        .take(max as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(anyhow!("line too long or cut short"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(raw: &[u8], max: usize) -> anyhow::Result<usize> {
+    std::str::from_utf8(raw)
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| *n <= max)
+        .ok_or_else(|| anyhow!("invalid length '{}'", show_key(raw)))
+}
+
+struct Session {
+    state: AppState,
+    // Set by AUTH, only looked at when auth is configured
+    grants: Option<Arc<Grants>>,
+}
+
+impl Session {
+    async fn run(&mut self, mut args: Vec<Vec<u8>>) -> Reply {
+        let name = String::from_utf8_lossy(&args.remove(0)).to_ascii_uppercase();
+        debug!(command = %name, args = args.len(), "RESP command");
+        match name.as_str() {
+            "AUTH" => self.auth(args),
+            // Anything that doesn't touch keys is fine before AUTH, clients send these
+            // while connecting
+            "PING" => match args.pop() {
+                Some(msg) if args.is_empty() => Reply::Bulk(msg),
+                None => Reply::Simple("PONG"),
+                _ => wrong_args(&name),
+            },
+            "ECHO" => match <[_; 1]>::try_from(args) {
+                Ok([msg]) => Reply::Bulk(msg),
+                Err(_) => wrong_args(&name),
+            },
+            "SELECT" => match args.as_slice() {
+                [db] if db == b"0" => Reply::Simple("OK"),
+                [_] => Reply::err("DB index is out of range"),
+                _ => wrong_args(&name),
+            },
+            "CLIENT" | "QUIT" => Reply::Simple("OK"),
+            "COMMAND" => Reply::Array(Vec::new()),
+            _ if self.state.auth.is_some() && self.grants.is_none() => {
+                Reply::Error("NOAUTH Authentication required.".to_string())
+            }
+            "GET" => self.get(&name, args).await.into(),
+            "SET" => self.set(&name, args).await.into(),
+            "DEL" => self.del(&name, args).await.into(),
+            "SCAN" => self.scan(&name, args).await.into(),
+            "HTTPKV.VERSION" => self.version(&name, args).await.into(),
+            _ => Reply::err(format!("unknown command '{}'", name)),
+        }
+    }
+
+    fn auth(&mut self, mut args: Vec<Vec<u8>>) -> Reply {
+        let Some(auth) = &self.state.auth else {
+            return Reply::err("AUTH called without any tokens configured");
+        };
+        // `AUTH username token` is fine too, the username means nothing to us
+        let token = match args.len() {
+            1 | 2 => args.pop().unwrap(),
+            _ => return wrong_args("AUTH"),
+        };
+        match auth.grants(&String::from_utf8_lossy(&token)) {
+            Some(grants) => {
+                self.grants = Some(grants);
+                Reply::Simple("OK")
+            }
+            None => Reply::Error("WRONGPASS unknown token".to_string()),
+        }
+    }
+
+    fn check(&self, permission: Permission, key: &[u8]) -> Result<(), Reply> {
+        match &self.grants {
+            Some(grants) if !grants.allows(permission, key) => Err(Reply::Error(format!(
+                "NOPERM token can't {:?} {}",
+                permission,
+                show_key(key)
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_writable(&self) -> Result<(), Reply> {
+        match self.state.leader {
+            Some(_) => Err(Reply::Error(
+                "READONLY You can't write against a read only replica.".to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    async fn get(&self, name: &str, args: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+        let [key] = <[_; 1]>::try_from(args).map_err(|_| wrong_args(name))?;
+        self.check(Permission::Read, &key)?;
+        let kv = self.state.kv.read(&[&key]).await;
+        Ok(match kv.get_live(&key) {
+            Some(item) => Reply::Bulk(item.data.clone()),
+            None => Reply::Nil,
+        })
+    }
+
+    async fn set(&self, name: &str, mut args: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+        if args.len() < 2 {
+            return Err(wrong_args(name));
+        }
+        let options = args.split_off(2);
+        let data = args.pop().unwrap();
+        let key = args.pop().unwrap();
+        self.check_writable()?;
+        self.check(Permission::Write, &key)?;
+
+        let (mut nx, mut xx, mut expires_at) = (false, false, None);
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let option = String::from_utf8_lossy(option).to_ascii_uppercase();
+            match option.as_str() {
+                "NX" => nx = true,
+                "XX" => xx = true,
+                "EX" | "PX" if expires_at.is_none() => {
+                    let n = options
+                        .next()
+                        .and_then(|n| std::str::from_utf8(n).ok())
+                        .and_then(|n| n.parse::<i64>().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| Reply::err("invalid expire time in 'set' command"))?;
+                    let millis = if option == "EX" {
+                        n.checked_mul(1000)
+                    } else {
+                        Some(n)
+                    };
+                    expires_at = millis.and_then(|millis| crate::now_millis().checked_add(millis));
+                    if expires_at.is_none() {
+                        return Err(Reply::err("invalid expire time in 'set' command"));
+                    }
+                }
+                _ => return Err(Reply::err("syntax error")),
+            }
+        }
+        if nx && xx {
+            return Err(Reply::err("syntax error"));
+        }
+
+        let mut kv = self.state.kv.write(&[&key]).await;
+        let exists = kv.get_live(&key).is_some();
+        if (nx && exists) || (xx && !exists) {
+            return Ok(Reply::Nil);
+        }
+        if let Some(quotas) = self.state.kv.quotas() {
+            quotas
+                .check_write(&key, kv.get(&key), data.len())
+                .map_err(app_error)?;
+        }
+        let version = kv.next_version();
+        kv.put(
+            key,
+            Item {
+                version,
+                data,
+                expires_at,
+                meta: Meta::default(),
+            },
+        )
+        .map_err(internal)?;
+        Ok(Reply::Simple("OK"))
+    }
+
+    async fn del(&self, name: &str, args: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+        if args.is_empty() {
+            return Err(wrong_args(name));
+        }
+        self.check_writable()?;
+        // Deleting a key twice in one command only counts once
+        let keys: BTreeSet<Vec<u8>> = args.into_iter().collect();
+        for key in &keys {
+            self.check(Permission::Write, key)?;
+        }
+        let keys: Vec<Vec<u8>> = keys.into_iter().collect();
+
+        let mut kv = self.state.kv.write(&keys).await;
+        let version = kv.next_version();
+        let ops: Vec<Op> = keys
+            .into_iter()
+            .filter(|key| kv.get_live(key).is_some())
+            .map(|key| Op::Delete { key, version })
+            .collect();
+        let count = ops.len();
+        kv.apply(ops).map_err(internal)?;
+        Ok(Reply::Integer(count as i64))
+    }
+
+    /// Looks at up to COUNT keys after the cursor, so a page can come back with fewer
+    /// (or no) keys even though there are more to go, same as Redis
+    async fn scan(&self, name: &str, args: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+        let mut args = args.into_iter();
+        let cursor = args.next().ok_or_else(|| wrong_args(name))?;
+        let after = match cursor.as_slice() {
+            b"0" => None,
+            cursor => Some(
+                URL_SAFE_NO_PAD
+                    .decode(cursor)
+                    .map_err(|_| Reply::err("invalid cursor"))?,
+            ),
+        };
+        let (mut pattern, mut count) = (None, DEFAULT_SCAN_COUNT);
+        while let Some(option) = args.next() {
+            let value = args.next().ok_or_else(|| Reply::err("syntax error"))?;
+            match String::from_utf8_lossy(&option)
+                .to_ascii_uppercase()
+                .as_str()
+            {
+                "MATCH" => pattern = Some(value),
+                "COUNT" => {
+                    count = std::str::from_utf8(&value)
+                        .ok()
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| Reply::err("value is out of range, must be positive"))?
+                        .min(MAX_SCAN_COUNT);
+                }
+                _ => return Err(Reply::err("syntax error")),
+            }
+        }
+
+        // Everything a pattern can match starts with the part before its first wildcard
+        let prefix = pattern.as_deref().map_or(&[][..], literal_prefix);
+        self.check(Permission::List, prefix)?;
+        let prefix_end = prefix_end(prefix);
+        let lower = match &after {
+            Some(after) if after.as_slice() >= prefix => Bound::Excluded(after.as_slice()),
+            _ => Bound::Included(prefix),
+        };
+        let upper = match &prefix_end {
+            Some(end) => Bound::Excluded(end.as_slice()),
+            None => Bound::Unbounded,
+        };
+        if let (Bound::Excluded(l), Bound::Excluded(u)) = (lower, upper) {
+            if l >= u {
+                return Ok(Reply::Array(vec![
+                    Reply::Bulk(b"0".to_vec()),
+                    Reply::Array(Vec::new()),
+                ]));
+            }
+        }
+
+        let kv = self.state.kv.read_range((lower, upper)).await;
+        let mut keys = Vec::new();
+        let mut last = None;
+        // One extra so we know whether there's anything after this page
+        let mut seen = kv.range((lower, upper)).take(count.saturating_add(1));
+        for (key, item) in seen.by_ref().take(count) {
+            last = Some(key);
+            if !item.is_expired() && pattern.as_deref().is_none_or(|p| glob_match(p, key)) {
+                keys.push(Reply::Bulk(key.clone()));
+            }
+        }
+        let cursor = match (seen.next(), last) {
+            (Some(_), Some(last)) => URL_SAFE_NO_PAD.encode(last).into_bytes(),
+            _ => b"0".to_vec(),
+        };
+        Ok(Reply::Array(vec![Reply::Bulk(cursor), Reply::Array(keys)]))
+    }
+
+    async fn version(&self, name: &str, args: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+        match <[_; 1]>::try_from(args) {
+            Ok([key]) => {
+                self.check(Permission::Read, &key)?;
+                let kv = self.state.kv.read(&[&key]).await;
+                Ok(match kv.get_live(&key) {
+                    Some(item) => Reply::Integer(item.version),
+                    None => Reply::Nil,
+                })
+            }
+            Err(args) if args.is_empty() => Ok(Reply::Integer(self.state.kv.revision())),
+            Err(_) => Err(wrong_args(name)),
+        }
+    }
+}
+
+impl From<Result<Reply, Reply>> for Reply {
+    fn from(reply: Result<Reply, Reply>) -> Self {
+        match reply {
+            Ok(reply) | Err(reply) => reply,
+        }
+    }
+}
+
+fn wrong_args(name: &str) -> Reply {
+    Reply::err(format!(
+        "wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+fn internal(e: anyhow::Error) -> Reply {
+    warn!("RESP command failed: {:?}", e);
+    Reply::err(e)
+}
+
+fn app_error(e: AppError) -> Reply {
+    match e {
+        AppError::CustomCode(e, _) => Reply::err(e),
+        AppError::Anyhow(e) => internal(e),
+    }
+}
+
+/// The pattern up to its first special character
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|b| matches!(b, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Redis glob patterns: `*`, `?`, `[abc]`, `[^a-z]` and `\` to escape. When what
+/// follows a `*` stops matching, only the last `*` takes one more byte and tries again,
+/// so there's no blowing up on patterns with a lot of them.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Just after the last `*`, and where in the key it's matched up to
+    let mut star = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, k));
+        } else if let Some(len) = match_one(&pattern[p..], key[k]) {
+            p += len;
+            k += 1;
+        } else if let Some((after_star, matched)) = star {
+            p = after_star;
+            k = matched + 1;
+            star = Some((after_star, k));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// If the pattern's first token matches `b`, how long the token is
+fn match_one(pattern: &[u8], b: u8) -> Option<usize> {
+    match pattern.split_first()? {
+        (b'?', _) => Some(1),
+        (b'[', rest) => {
+            let (negate, rest) = match rest.split_first() {
+                Some((b'^', rest)) => (true, rest),
+                _ => (false, rest),
+            };
+            let Some(close) = rest.iter().skip(1).position(|c| *c == b']').map(|n| n + 1) else {
+                // No closing bracket, so it's just a '['
+                return (b == b'[').then_some(1);
+            };
+            let class = &rest[..close];
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if class[i] == b'\\' && i + 1 < class.len() {
+                    i += 1;
+                    matched |= class[i] == b;
+                } else if i + 2 < class.len() && class[i + 1] == b'-' {
+                    let (lo, hi) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+                    matched |= (lo..=hi).contains(&b);
+                    i += 2;
+                } else {
+                    matched |= class[i] == b;
+                }
+                i += 1;
+            }
+            (matched != negate).then_some(1 + negate as usize + close + 1)
+        }
+        (b'\\', [escaped, ..]) => (*escaped == b).then_some(2),
+        (&c, _) => (c == b).then_some(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_commands() {
+        let limits = Limits {
+            args: MAX_ARGS,
+            bulk_bytes: 64 << 20,
+        };
+        let mut input: &[u8] =
+            b"*3\r\n$3\r\nSET\r\n$2\r\na\xff\r\n$4\r\n1\r\n2\r\nGET  a\r\n*1\r\n$4\r\nPING";
+        let args = read_command(&mut input, limits).await.unwrap().unwrap();
+        assert_eq!(
+            args,
+            vec![b"SET".to_vec(), b"a\xff".to_vec(), b"1\r\n2".to_vec()]
+        );
+        let args = read_command(&mut input, limits).await.unwrap().unwrap();
+        assert_eq!(args, vec![b"GET".to_vec(), b"a".to_vec()]);
+        assert!(read_command(&mut input, limits).await.is_err());
+        assert!(read_command(&mut input, limits).await.unwrap().is_none());
+
+        // Before AUTH, nobody gets to send much
+        let mut input: &[u8] = b"*11\r\n";
+        assert!(read_command(&mut input, UNAUTHENTICATED_LIMITS)
+            .await
+            .is_err());
+        let mut input: &[u8] = b"*2\r\n$4\r\nAUTH\r\n$67108864\r\n";
+        assert!(read_command(&mut input, UNAUTHENTICATED_LIMITS)
+            .await
+            .is_err());
+        // A length that never arrives isn't allocated up front
+        let mut input: &[u8] = b"*1\r\n$67108864\r\nshort";
+        assert!(read_command(&mut input, limits).await.is_err());
+
+        let mut buf = Vec::new();
+        Reply::Array(vec![
+            Reply::Bulk(b"x".to_vec()),
+            Reply::Nil,
+            Reply::Integer(3),
+        ])
+        .encode(&mut buf);
+        assert_eq!(buf, b"*3\r\n$1\r\nx\r\n$-1\r\n:3\r\n");
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"a\\*", b"a*"));
+        assert!(!glob_match(b"a\\*", b"ab"));
+        assert!(glob_match(b"*[*]*", b"a*b"));
+        assert!(glob_match(b"a*b*c", b"axxbyyc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyy"));
+        // Exponential when every `*` backtracks on its own
+        let pattern = [b"a*".repeat(50), b"b".to_vec()].concat();
+        assert!(!glob_match(&pattern, &[b'a'; 10_000]));
+        assert_eq!(literal_prefix(b"user:*:name"), b"user:");
+    }
+}
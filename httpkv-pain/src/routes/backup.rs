@@ -0,0 +1,152 @@
+use std::{collections::HashSet, convert::Infallible, ops::Bound};
+
+use crate::{
+    backup::{self, Encoder},
+    storage::{entry_size, Op},
+    AppError, AppState,
+};
+use anyhow::anyhow;
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::Response,
+};
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use tracing::info;
+
+// A page of a backup stops at whichever of these it reaches first
+const PAGE_KEYS: usize = 1000;
+const PAGE_BYTES: usize = 4 << 20;
+
+/// Streams every live key in the backup format from [`crate::backup`]. Keys are read a
+/// page at a time, each under its own read lock, so writers only wait on a page and
+/// there's never more than one page held in memory.
+#[tracing::instrument(level = "debug", skip(state))]
+pub async fn backup(State(state): State<AppState>) -> Result<Response, AppError> {
+    let revision = state.kv.revision();
+    let (encoder, header) = Encoder::new(revision);
+    info!(revision = revision, "streaming backup");
+
+    let pages = stream::unfold(Some((encoder, None)), move |next| {
+        let kv = state.kv.clone();
+        async move {
+            let (mut encoder, after): (Encoder, Option<Vec<u8>>) = next?;
+            let lower = match &after {
+                Some(after) => Bound::Excluded(after.as_slice()),
+                None => Bound::Unbounded,
+            };
+            let range = (lower, Bound::Unbounded);
+            let (mut page, more) = {
+                let view = kv.read_range(range).await;
+                let mut page = Vec::new();
+                let mut bytes = 0;
+                let mut more = false;
+                for (key, item) in view.range(range) {
+                    if page.len() == PAGE_KEYS || bytes >= PAGE_BYTES {
+                        more = true;
+                        break;
+                    }
+                    bytes += entry_size(key, item);
+                    page.push((key.clone(), item.clone()));
+                }
+                (page, more)
+            };
+
+            let mut chunk = Vec::new();
+            for (key, item) in page.iter().filter(|(_, item)| !item.is_expired()) {
+                chunk.extend(encoder.entry(key, item));
+            }
+            let next = match page.pop() {
+                Some((last, _)) if more => Some((encoder, Some(last))),
+                _ => {
+                    chunk.extend(encoder.finish());
+                    None
+                }
+            };
+            Some((Ok::<_, Infallible>(chunk), next))
+        }
+    });
+    let chunks = stream::once(async { Ok(header) }).chain(pages);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::from_stream(chunks))
+        .expect("Failed to construct response"))
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RestoreMode {
+    /// The store ends up holding exactly what's in the backup
+    Replace,
+    /// Keys in the backup overwrite ours, everything else is left alone
+    #[default]
+    Merge,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RestoreParams {
+    #[serde(default)]
+    mode: RestoreMode,
+}
+
+/// Loads a backup stream. The whole thing is verified before anything is written, and
+/// then it's applied as one batch.
+///
+/// If the store is behind every version in the backup (e.g. it's empty) the versions are
+/// kept as they were, so clients' version conditions keep working after a migration.
+/// Otherwise the restored keys are written with a new version like any other write.
+#[tracing::instrument(level = "debug", skip(state, buf))]
+pub async fn restore(
+    State(state): State<AppState>,
+    Query(params): Query<RestoreParams>,
+    buf: Bytes,
+) -> Result<String, AppError> {
+    let backup = backup::decode(&buf).map_err(|e| {
+        AppError::CustomCode(anyhow!("Invalid backup: {}", e), StatusCode::BAD_REQUEST)
+    })?;
+
+    let everything = (Bound::Unbounded, Bound::Unbounded);
+    let mut kv = state.kv.write_range(everything).await;
+    let oldest = backup.entries.iter().map(|(_, item)| item.version).min();
+    let keep_versions = oldest.is_none_or(|oldest| oldest > kv.revision());
+    let version = if keep_versions {
+        // Keys written while the backup ran can be newer than its revision
+        let newest = backup.entries.iter().map(|(_, item)| item.version).max();
+        kv.revision().max(backup.revision).max(newest.unwrap_or(0)) + 1
+    } else {
+        kv.next_version()
+    };
+
+    let mut ops = Vec::with_capacity(backup.entries.len());
+    if let RestoreMode::Replace = params.mode {
+        let restored: HashSet<&Vec<u8>> = backup.entries.iter().map(|(key, _)| key).collect();
+        ops.extend(
+            kv.range(everything)
+                .filter(|(key, _)| !restored.contains(key))
+                .map(|(key, _)| Op::Delete {
+                    key: key.clone(),
+                    version,
+                }),
+        );
+    }
+    let count = backup.entries.len();
+    ops.extend(backup.entries.into_iter().map(|(key, mut item)| {
+        if !keep_versions {
+            item.version = version;
+        }
+        Op::Put { key, item }
+    }));
+
+    kv.apply(ops)?;
+    info!(
+        mode = ?params.mode,
+        keys = count,
+        keep_versions = keep_versions,
+        "restored backup"
+    );
+
+    Ok(count.to_string())
+}
@@ -0,0 +1,226 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    auth::{Grants, Permission},
+    routes::{post::check_conditions, EncodedKey, EncodedValue, Encoding, JsonKey},
+    storage::Op,
+    AppError, AppState, Item, Meta,
+};
+use axum::{extract::State, http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+#[derive(Deserialize, Debug)]
+pub struct MultiGetRequest {
+    keys: Vec<String>,
+    /// Applies to every key
+    key_encoding: Option<Encoding>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MultiGetResponse {
+    /// Every result was read at this revision
+    revision: i64,
+    results: Vec<GetResult>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GetResult {
+    #[serde(flatten)]
+    key: EncodedKey,
+    found: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    value: Option<EncodedValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+}
+
+/// Reads every key under one read lock, results in the same order as the keys
+#[tracing::instrument(level = "debug", skip(state, grants, req), fields(keys = req.keys.len()))]
+pub async fn multi_get(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Json(req): Json<MultiGetRequest>,
+) -> Result<Json<MultiGetResponse>, AppError> {
+    let keys = req
+        .keys
+        .into_iter()
+        .map(|key| {
+            EncodedKey::try_from(JsonKey {
+                key,
+                key_encoding: req.key_encoding,
+            })
+            .map_err(|e| AppError::CustomCode(e, StatusCode::BAD_REQUEST))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(Extension(grants)) = grants {
+        for key in &keys {
+            grants.check(Permission::Read, &key.0)?;
+        }
+    }
+
+    let kv = state
+        .kv
+        .read(&keys.iter().map(|key| &key.0).collect::<Vec<_>>())
+        .await;
+    let results = keys
+        .into_iter()
+        .map(|key| match kv.get_live(&key.0) {
+            Some(item) => GetResult {
+                found: true,
+                value: Some(EncodedValue::new(&item.data)),
+                version: Some(item.version),
+                key,
+            },
+            None => GetResult {
+                key,
+                found: false,
+                value: None,
+                version: None,
+            },
+        })
+        .collect();
+
+    Ok(Json(MultiGetResponse {
+        revision: kv.revision(),
+        results,
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MultiPutRequest {
+    items: Vec<PutItem>,
+}
+
+/// Same conditions as the query params on a single write
+#[derive(Deserialize, Debug)]
+pub struct PutItem {
+    #[serde(flatten)]
+    key: EncodedKey,
+    #[serde(flatten)]
+    value: EncodedValue,
+    #[serde(default, alias = "nx")]
+    not_exists: bool,
+    #[serde(default, alias = "ix")]
+    if_exists: bool,
+    version: Option<i64>,
+    expect: Option<String>,
+    // Seconds from now
+    ttl: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MultiPutResponse {
+    results: Vec<PutResult>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum PutResult {
+    Written {
+        #[serde(flatten)]
+        key: EncodedKey,
+        version: i64,
+    },
+    Failed {
+        #[serde(flatten)]
+        key: EncodedKey,
+        status: u16,
+        error: String,
+    },
+}
+
+/// Writes every item whose conditions hold, in one batch under one new version. An item
+/// that fails doesn't stop the others. Later items see earlier ones, so putting the
+/// same key twice with `nx` fails the second.
+#[tracing::instrument(level = "debug", skip(state, grants, req), fields(items = req.items.len()))]
+pub async fn multi_put(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Json(req): Json<MultiPutRequest>,
+) -> Result<Json<MultiPutResponse>, AppError> {
+    // Like a txn, an unauthorized key fails the whole request rather than one item
+    if let Some(Extension(grants)) = grants {
+        for item in &req.items {
+            grants.check(Permission::Write, &item.key.0)?;
+        }
+    }
+
+    let keys: Vec<&[u8]> = req.items.iter().map(|item| item.key.0.as_slice()).collect();
+    let mut kv = state.kv.write(&keys).await;
+    let version = kv.next_version();
+    let expires_from = crate::now_millis();
+    let mut staged: HashMap<Vec<u8>, Item> = HashMap::new();
+    let mut quotas = state.kv.quotas().map(|quotas| quotas.batch());
+    let mut results = Vec::with_capacity(req.items.len());
+    for put in req.items {
+        let current = staged.get(&put.key.0).or_else(|| kv.get_live(&put.key.0));
+        let checked = check_conditions(
+            &put.key.0,
+            current,
+            put.not_exists,
+            put.if_exists,
+            put.version,
+            put.expect.as_deref().map(str::as_bytes),
+        )
+        .and_then(|_| put.value.into_bytes())
+        .and_then(|data| {
+            let expires_at = put
+                .ttl
+                .map(|ttl| crate::expiry_after(expires_from, ttl))
+                .transpose()?;
+            Ok((data, expires_at))
+        })
+        .and_then(|(data, expires_at)| match &mut quotas {
+            Some(quotas) => {
+                // Expired keys count until they're reaped
+                let old = staged.get(&put.key.0).or_else(|| kv.get(&put.key.0));
+                quotas
+                    .check_write(&put.key.0, old, data.len())
+                    .map(|_| (data, expires_at))
+            }
+            None => Ok((data, expires_at)),
+        });
+        match checked {
+            Ok((data, expires_at)) => {
+                staged.insert(
+                    put.key.0.clone(),
+                    Item {
+                        version,
+                        data,
+                        expires_at,
+                        meta: Meta::default(),
+                    },
+                );
+                results.push(PutResult::Written {
+                    key: put.key,
+                    version,
+                });
+            }
+            Err(e) => {
+                let (status, error) = match e {
+                    AppError::CustomCode(e, status) => (status, e.to_string()),
+                    AppError::Anyhow(e) => return Err(AppError::Anyhow(e)),
+                };
+                results.push(PutResult::Failed {
+                    key: put.key,
+                    status: status.as_u16(),
+                    error,
+                });
+            }
+        }
+    }
+
+    let written = staged.len();
+    if written > 0 {
+        kv.apply(
+            staged
+                .into_iter()
+                .map(|(key, item)| Op::Put { key, item })
+                .collect(),
+        )?;
+    }
+    info!(written = written, version = version, "multi put");
+
+    Ok(Json(MultiPutResponse { results }))
+}
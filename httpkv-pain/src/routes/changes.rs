@@ -0,0 +1,92 @@
+use crate::{
+    storage::{encode_record, Compacted, Format},
+    watch::Subscription,
+    AppError, AppState,
+};
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::Response,
+};
+use futures::{stream, StreamExt};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn};
+
+/// Set on the response when the ops are laid out as `Format::V2`
+pub(crate) const FORMAT_HEADER: &str = "x-httpkv-format";
+
+#[derive(Deserialize, Debug)]
+pub struct ChangesParams {
+    from: i64,
+    // Followers that understand meta ask for 2, older ones don't ask
+    format: Option<u32>,
+}
+
+/// The ordered change log for followers: every batch applied after `from`, then every
+/// new one as it's applied. Each batch is framed like a WAL record,
+/// `[len u32 LE][crc32 u32 LE][bincode Vec<Op>]`. Followers that don't ask for
+/// `format=2` get items without their meta, in the layout they know.
+///
+/// 410 if `from` is older than the history we keep, 409 if it's newer than anything
+/// we've written. Either way the follower has to start over from a backup.
+#[tracing::instrument(level = "debug", skip(state))]
+pub async fn changes(
+    State(state): State<AppState>,
+    Query(params): Query<ChangesParams>,
+) -> Result<Response, AppError> {
+    let from = params.from;
+    let revision = state.kv.revision();
+    if from > revision {
+        return Err(AppError::CustomCode(
+            anyhow!("Version {} is ahead of our revision {}", from, revision),
+            StatusCode::CONFLICT,
+        ));
+    }
+    let Subscription { replay, rx } =
+        state
+            .watcher
+            .subscribe(Some(from))
+            .map_err(|Compacted { oldest }| {
+                AppError::CustomCode(
+                    anyhow!(
+                        "Version {} is too old to resume from, oldest available is {}",
+                        from,
+                        oldest
+                    ),
+                    StatusCode::GONE,
+                )
+            })?;
+    info!(from = from, revision = revision, "follower connected");
+
+    let live = stream::unfold(rx, |mut rx| async move {
+        match rx.recv().await {
+            Ok(batch) => Some((batch, rx)),
+            Err(RecvError::Lagged(skipped)) => {
+                // The follower reconnects from the last batch it applied
+                warn!(skipped = skipped, "Follower fell behind, closing stream");
+                None
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+    let format = match params.format {
+        Some(2) => Format::V2,
+        _ => Format::V1,
+    };
+    let records = stream::iter(replay)
+        .chain(live)
+        .map(move |batch| encode_record(&batch.ops, format));
+
+    let mut res = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream");
+    if format == Format::V2 {
+        res = res.header(FORMAT_HEADER, "2");
+    }
+    Ok(res
+        .body(Body::from_stream(records))
+        .expect("Failed to construct response"))
+}
@@ -0,0 +1,123 @@
+use std::ops::Bound;
+
+use crate::{
+    routes::{show_key, unencode_key, Encoding, Key, Preconditions},
+    storage::{prefix_end, Op},
+    AppError, AppState,
+};
+use axum::extract::{Query, State};
+use anyhow::anyhow;
+use serde::Deserialize;
+use tracing::info;
+use validator::Validate;
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct DeleteParams {
+    #[serde(default, alias = "ix")]
+    if_exists: Option<String>,
+
+    #[serde(default, alias = "v")]
+    version: Option<i64>,
+
+    // Range params, deletes every key starting with the path key
+    prefix: Option<String>,
+    // or every key from the path key up to (not including) this one
+    end: Option<String>,
+    // Applies to `end` as well as the path key
+    key_encoding: Option<Encoding>,
+}
+
+/// Returns how many keys were removed
+#[tracing::instrument(level = "debug", skip(state))]
+pub async fn delete_key(
+    Key(key): Key,
+    State(state): State<AppState>,
+    Query(params): Query<DeleteParams>,
+    preconditions: Preconditions,
+) -> Result<String, AppError> {
+    params.validate()?;
+
+    if params.prefix.is_some() || params.end.is_some() {
+        if params.if_exists.is_some() || params.version.is_some() || preconditions.is_set() {
+            return Err(AppError::CustomCode(
+                anyhow!("ix, version, If-Match and If-None-Match can't be used with prefix or end"),
+                axum::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+        return delete_range(state, &key, &params).await;
+    }
+
+    let mut kv = state.kv.write(&[&key]).await;
+    preconditions.check(&key, kv.get_live(&key))?;
+    match kv.get_live(&key) {
+        Some(item) => {
+            if let Some(version) = params.version {
+                if version != item.version {
+                    return Err(AppError::CustomCode(
+                        anyhow!(
+                            "Provided version {} does not match found version {}",
+                            version,
+                            item.version
+                        ),
+                        axum::http::StatusCode::CONFLICT,
+                    ));
+                }
+            }
+        }
+        None => {
+            if params.if_exists.is_some() {
+                return Err(AppError::CustomCode(
+                    anyhow!("Key {} doesn't exist (ix)", show_key(&key)),
+                    axum::http::StatusCode::CONFLICT,
+                ));
+            }
+            return Ok("0".to_string());
+        }
+    }
+
+    kv.delete(key)?;
+    info!("deleted it");
+
+    Ok("1".to_string())
+}
+
+async fn delete_range(
+    state: AppState,
+    start: &[u8],
+    params: &DeleteParams,
+) -> Result<String, AppError> {
+    let end = match &params.end {
+        Some(end) => Some(unencode_key(end.clone().into_bytes(), params.key_encoding)?),
+        None => None,
+    };
+    if end.as_deref().is_some_and(|end| end <= start) {
+        // BTreeMap::range panics on a backwards range
+        return Ok("0".to_string());
+    }
+    // Only lock the partitions we might delete from
+    let prefix_end = params.prefix.as_ref().and_then(|_| prefix_end(start));
+    let range = (
+        Bound::Included(start),
+        match end.as_ref().or(prefix_end.as_ref()) {
+            Some(end) => Bound::Excluded(end.as_slice()),
+            None => Bound::Unbounded,
+        },
+    );
+    let mut kv = state.kv.write_range(range).await;
+    let version = kv.next_version();
+    let ops: Vec<Op> = kv
+        .range(range)
+        .take_while(|(key, _)| params.prefix.is_none() || key.starts_with(start))
+        .filter(|(_, item)| !item.is_expired())
+        .map(|(key, _)| Op::Delete {
+            key: key.clone(),
+            version,
+        })
+        .collect();
+
+    let count = ops.len();
+    kv.apply(ops)?;
+    info!(start = %show_key(start), end = params.end, count = count, "deleted range");
+
+    Ok(count.to_string())
+}
@@ -1,15 +1,30 @@
-use crate::{AppError, AppState};
+use crate::{
+    routes::{
+        etag, meta_headers, show_key, watch::watch_items, EncodedKey, EncodedValue, EntityTags, Key,
+    },
+    storage::{prefix_end, Compacted, Revision},
+    AppError, AppState, Item,
+};
 use axum::{
-    extract::{Path, Query, State},
-    http::{HeaderValue, StatusCode},
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
 // use axum_extra::extract::Query;
 use anyhow::anyhow;
-use serde::Deserialize;
-use tracing::{debug, debug_span, event};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, convert::Infallible, ops::Bound};
+use tracing::debug;
 use validator::Validate;
 
+// Values bigger than this go out a chunk at a time rather than as one buffer
+const STREAM_THRESHOLD: usize = 1 << 20;
+const STREAM_CHUNK: usize = 64 << 10;
+/// The most items a page of a list or query holds, whatever `limit` asks for
+const MAX_PAGE_LIMIT: i64 = 10_000;
+
 #[derive(Deserialize, Debug, Validate)]
 pub struct GetOrListParams {
     list: Option<String>,
@@ -19,163 +34,493 @@ pub struct GetOrListParams {
     #[serde(default, alias = "vals")]
     with_vals: Option<String>,
     reverse: Option<String>,
-    start: Option<i64>,
-    end: Option<i64>,
+    // Cursor from the previous page's `cursor` header
+    after: Option<String>,
+    // With values, also the content type and x-meta fields they were written with
+    meta: Option<String>,
+
+    // Read the key as of this version
+    version: Option<i64>,
+    // List every revision we still have for the key
+    history: Option<String>,
+
+    // Watch params
+    watch: Option<String>,
+    prefix: Option<String>,
+    from: Option<i64>,
 }
 
 pub async fn get_root(
     State(state): State<AppState>,
     Query(params): Query<GetOrListParams>,
+    headers: HeaderMap,
 ) -> Result<Response, AppError> {
-    get_or_list_prefix(state, None, &params).await
+    get_or_list_prefix(state, None, &params, &headers).await
 }
 
 pub async fn get_key(
     State(state): State<AppState>,
-    Path(key_prefix): Path<String>,
+    Key(key_prefix): Key,
     Query(params): Query<GetOrListParams>,
+    headers: HeaderMap,
 ) -> Result<Response, AppError> {
-    get_or_list_prefix(state, Some(key_prefix), &params).await
+    get_or_list_prefix(state, Some(key_prefix), &params, &headers).await
 }
 
-#[tracing::instrument(level = "debug", skip(state))]
+#[tracing::instrument(level = "debug", skip(state, headers))]
 pub async fn get_or_list_prefix(
     state: AppState,
-    key_prefix: Option<String>,
+    key_prefix: Option<Vec<u8>>,
     params: &GetOrListParams,
+    headers: &HeaderMap,
 ) -> Result<Response, AppError> {
     params.validate()?;
 
+    if params.watch.is_some() {
+        let sse = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/event-stream"));
+        // Watching the root watches everything
+        let prefix = params.prefix.is_some() || key_prefix.is_none();
+        return watch_items(
+            state,
+            key_prefix.unwrap_or_default(),
+            prefix,
+            params.from,
+            params.with_vals.is_some(),
+            sse,
+        )
+        .await;
+    }
+
     // Check if we are a list
     match &params.list {
         Some(list) if list.is_empty() => {
-            return list_items(state, params, key_prefix).await;
+            return list_items(state, params, key_prefix, headers).await;
         }
         _ => {}
     }
 
     if let Some(key) = key_prefix {
-        get_item(state, params, &key).await
+        if params.history.is_some() {
+            return key_history(state, &key).await;
+        }
+        get_item(state, params, &key, headers, false).await
     } else {
         // Just a health check
         Ok("alive".into_response())
     }
 }
 
-#[tracing::instrument(level = "debug", skip(state))]
+/// The headers a GET of the key would send, without the value
+pub async fn head_key(
+    State(state): State<AppState>,
+    Key(key): Key,
+    Query(params): Query<GetOrListParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    params.validate()?;
+    get_item(state, &params, &key, &headers, true).await
+}
+
+/// Answers `If-None-Match` with a 304, and `Range` with a 206
+#[tracing::instrument(level = "debug", skip(state, headers))]
 async fn get_item(
     state: AppState,
     params: &GetOrListParams,
-    key: &String,
+    key: &[u8],
+    headers: &HeaderMap,
+    head: bool,
 ) -> Result<Response, AppError> {
-    let kv = state.kv.read().await;
-    if let Some(val) = kv.get(key) {
-        let mut body = val.data.clone();
-        if params.start.is_some() || params.end.is_some() {
-            // We need to get a subslice of the body
-            let start = params.start.or(Some(0)).unwrap() as usize;
-            let end = params.end.or(Some(body.len() as i64)).unwrap() as usize;
-            debug!(
-                "Getting subslice of value for key {} with start={} end={}",
-                key, start, end
-            );
-            body = body[start..end].to_vec();
+    let kv = state.kv.read(&[key]).await;
+    let found = match params.version {
+        Some(version) => kv.get_at(key, version).map_err(|Compacted { oldest }| {
+            AppError::CustomCode(
+                anyhow!(
+                    "Version {} has been compacted, oldest available is {}",
+                    version,
+                    oldest
+                ),
+                StatusCode::GONE,
+            )
+        })?,
+        None => kv.get_live(key),
+    };
+    if let Some(val) = found {
+        if EntityTags::from_header(headers, header::IF_NONE_MATCH)
+            .is_some_and(|tags| tags.matches(Some(val)))
+        {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header("version", HeaderValue::from(val.version))
+                .header(header::ETAG, etag(val.version))
+                .body(Body::empty())
+                .expect("Failed to construct response"));
         }
 
-        Ok(Response::builder()
-            .status(StatusCode::OK)
+        let len = val.data.len();
+        let range = match headers.get(header::RANGE).and_then(|r| r.to_str().ok()) {
+            Some(range) => match parse_range(range, len) {
+                Ok(range) => range,
+                Err(_) => {
+                    // Can't go through AppError, 416 has to say how big the value is
+                    return Ok(Response::builder()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                        .body(format!("Range {} not satisfiable for {} bytes", range, len).into())
+                        .expect("Failed to construct response"));
+                }
+            },
+            None => None,
+        };
+
+        let mut res = Response::builder()
             .header("version", HeaderValue::from(val.version))
-            .body(body.into())
+            .header(header::ETAG, etag(val.version))
+            .header(header::ACCEPT_RANGES, "bytes");
+        res = meta_headers(res, &val.meta);
+        let range = match range {
+            Some(range) => {
+                debug!(
+                    "Getting subslice of value for key {} with start={} end={}",
+                    show_key(key),
+                    range.start,
+                    range.end
+                );
+                res = res.status(StatusCode::PARTIAL_CONTENT).header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, len),
+                );
+                range
+            }
+            None => {
+                res = res.status(StatusCode::OK);
+                0..len
+            }
+        };
+        if let Some(expires_at) = val.expires_at {
+            // Remaining seconds, rounded up so we never say 0 for a live key
+            let ttl = (expires_at - crate::now_millis() + 999) / 1000;
+            res = res.header("ttl", HeaderValue::from(ttl));
+        }
+        res = res.header(header::CONTENT_LENGTH, range.len());
+        if head {
+            return Ok(res
+                .body(Body::empty())
+                .expect("Failed to construct response"));
+        }
+        // The one copy, of just the range, so the lock isn't held while it's sent
+        let body = Bytes::copy_from_slice(&val.data[range]);
+        drop(kv);
+        Ok(res
+            .body(stream_body(body))
             .expect("Failed to construct response"))
     } else {
-        Err(AppError::CustomCode(anyhow!("not found"), StatusCode::NOT_FOUND))
+        Err(AppError::CustomCode(
+            anyhow!("not found"),
+            StatusCode::NOT_FOUND,
+        ))
     }
 }
 
+/// Big values go out as a stream of frames, each sliced off `body` as it's asked for.
+/// Slices share `body`'s buffer, so this doesn't copy it again.
+fn stream_body(body: Bytes) -> Body {
+    if body.len() <= STREAM_THRESHOLD {
+        return body.into();
+    }
+    Body::from_stream(futures::stream::unfold(body, |mut rest| async move {
+        if rest.is_empty() {
+            return None;
+        }
+        let chunk = rest.split_to(rest.len().min(STREAM_CHUNK));
+        Some((Ok::<_, Infallible>(chunk), rest))
+    }))
+}
+
+#[derive(Serialize)]
+struct HistoryItem {
+    version: i64,
+    deleted: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    value: Option<EncodedValue>,
+}
+
+/// Every revision of the key we still have, oldest first, as JSON
 #[tracing::instrument(level = "debug", skip(state))]
+async fn key_history(state: AppState, key: &[u8]) -> Result<Response, AppError> {
+    let kv = state.kv.read(&[key]).await;
+    let mut revisions: Vec<HistoryItem> = kv
+        .history(key)
+        .into_iter()
+        .map(|revision| match revision {
+            Revision::Put(item) => HistoryItem {
+                version: item.version,
+                deleted: false,
+                value: Some(EncodedValue::new(&item.data)),
+            },
+            Revision::Delete { version } => HistoryItem {
+                version: *version,
+                deleted: true,
+                value: None,
+            },
+        })
+        .collect();
+    if let Some(item) = kv.get_live(key) {
+        revisions.push(HistoryItem {
+            version: item.version,
+            deleted: false,
+            value: Some(EncodedValue::new(&item.data)),
+        });
+    }
+    if revisions.is_empty() {
+        return Err(AppError::CustomCode(
+            anyhow!("not found"),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    Ok(axum::Json(revisions).into_response())
+}
+
+#[derive(Serialize)]
+pub(crate) struct ListItem {
+    #[serde(flatten)]
+    key: EncodedKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    value: Option<EncodedValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<BTreeMap<String, String>>,
+}
+
+impl ListItem {
+    pub(crate) fn new(key: &[u8], item: &Item, with_vals: bool, with_meta: bool) -> Self {
+        let with_meta = with_vals && with_meta;
+        Self {
+            key: EncodedKey::new(key),
+            version: with_vals.then_some(item.version),
+            value: with_vals.then(|| EncodedValue::new(&item.data)),
+            content_type: item.meta.content_type.clone().filter(|_| with_meta),
+            meta: with_meta.then(|| item.meta.fields.clone()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ListPage {
+    pub(crate) items: Vec<ListItem>,
+    pub(crate) next: Option<String>,
+}
+
+enum ListFormat {
+    Text,
+    Json,
+    Ndjson,
+}
+
+#[tracing::instrument(level = "debug", skip(state, headers))]
 async fn list_items(
     state: AppState,
     params: &GetOrListParams,
-    prefix: Option<String>,
+    prefix: Option<Vec<u8>>,
+    headers: &HeaderMap,
 ) -> Result<Response, AppError> {
-    let mut items: Vec<u8> = Vec::new();
-    let kv = state.kv.read().await;
     let with_vals = params.with_vals.is_some();
+    let with_meta = params.meta.is_some();
+    let reverse = params.reverse.is_some();
+    let limit = page_limit(params.limit)?;
 
-    // Build the list of items
-    let range_start = prefix.clone().or(Some(String::from(""))).unwrap(); // "" is beginning of DB
-    let range_end = match prefix {
-        Some(p) => p.as_bytes().to_owned(),
-        None => vec![0xFF], // 0xFF is end of DB
+    let prefix = prefix.unwrap_or_default(); // "" is the whole DB
+    let prefix_end = prefix_end(&prefix);
+    let after = match &params.after {
+        Some(cursor) => Some(decode_cursor(cursor)?),
+        None => None,
     };
-    let range_end_str = String::from_utf8(range_end).unwrap();
-    let reverse = params.reverse.is_some();
-    let limit = params.limit.or(Some(100)).unwrap() as usize;
     debug!(
-        start = range_start,
-        end = range_end_str,
+        prefix = %show_key(&prefix),
+        after = after.as_deref().map(show_key).as_deref(),
         limit = limit,
         with_vals = with_vals,
         reverse = reverse,
         "Listing items",
     );
 
-    // Sometimes rust is a PITA and you just repeat yourself instead
-    if !reverse {
-        // Forward iterate
-        for (key, item) in kv.range(range_start..).take(limit) {
-            // Add the key
-            if with_vals {
-                items.extend(key.as_bytes());
-                items.extend(b"\n");
-                items.extend(item.data.clone());
-                items.extend("\n".as_bytes());
-                items.extend("\n".as_bytes());
-            } else {
-                items.extend(key.as_bytes());
-                items.extend("\n".as_bytes());
-            }
+    // The cursor is the last key of the previous page, so it moves the bound we're walking towards
+    let mut lower = Bound::Included(prefix.as_slice());
+    let mut upper = match &prefix_end {
+        Some(end) => Bound::Excluded(end.as_slice()),
+        None => Bound::Unbounded,
+    };
+    if let Some(after) = &after {
+        if !reverse && after >= &prefix {
+            lower = Bound::Excluded(after.as_slice());
+        } else if reverse && prefix_end.as_ref().is_none_or(|end| after < end) {
+            upper = Bound::Excluded(after.as_slice());
         }
+    }
+
+    let kv = state.kv.read_range((lower, upper)).await;
+    let mut page: Vec<(&Vec<u8>, &Item)> = Vec::new();
+    if !range_is_empty(lower, upper) {
+        let iter = kv.range((lower, upper));
+        let iter: Box<dyn Iterator<Item = (&Vec<u8>, &Item)>> = match reverse {
+            true => Box::new(iter.rev()),
+            false => iter,
+        };
+        // Grab one extra so we know if there's another page
+        page = iter
+            .filter(|(_, item)| !item.is_expired())
+            .take(limit + 1)
+            .collect();
+    }
+    let next = if page.len() > limit {
+        page.truncate(limit);
+        page.last().map(|(key, _)| encode_cursor(key))
     } else {
-        // Reverse iterate
-
-        // If we have a prefix, use it
-        let rev_range = match range_start.as_str() {
-            "" => {
-                let last_item = kv.last_key_value();
-                match last_item {
-                    Some(item) => {
-                        kv.range(..item.0.to_owned() + "~").rev() // temp for the map, just add something larger on the end, fdb client has just options for the range iterator https://docs.rs/foundationdb/latest/foundationdb/struct.RangeOption.html
-                    }
-                    None => kv.range(.."".to_string()).rev(),
-                }
+        None
+    };
+
+    let format = match headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+    {
+        Some(accept) if accept.contains("application/x-ndjson") => ListFormat::Ndjson,
+        Some(accept) if accept.contains("application/json") => ListFormat::Json,
+        _ => ListFormat::Text,
+    };
+
+    let (content_type, body) = match format {
+        ListFormat::Json => {
+            let page = ListPage {
+                items: page
+                    .iter()
+                    .map(|(key, item)| ListItem::new(key, item, with_vals, with_meta))
+                    .collect(),
+                next: next.clone(),
+            };
+            ("application/json", serde_json::to_vec(&page)?)
+        }
+        ListFormat::Ndjson => {
+            let mut body = Vec::new();
+            for (key, item) in &page {
+                serde_json::to_writer(&mut body, &ListItem::new(key, item, with_vals, with_meta))?;
+                body.push(b'\n');
             }
-            _ => kv.range(..range_start).rev(),
-        };
-        for (key, item) in rev_range.take(limit) {
-            // Add the key
-            if with_vals {
-                items.extend(key.as_bytes());
-                items.extend("\n".as_bytes());
-                items.extend(item.data.clone());
-                items.extend("\n".as_bytes());
-                items.extend("\n".as_bytes());
-            } else {
-                items.extend(key.as_bytes());
-                items.extend("\n".as_bytes());
+            ("application/x-ndjson", body)
+        }
+        ListFormat::Text => {
+            // Keys separated by \n, or key\nvalue separated by \n\n if we have values.
+            // Use JSON if keys or values can have newlines in them.
+            let sep: &[u8] = if with_vals { b"\n\n" } else { b"\n" };
+            let mut body = Vec::new();
+            for (i, (key, item)) in page.iter().enumerate() {
+                if i > 0 {
+                    body.extend(sep);
+                }
+                body.extend(key.iter());
+                if with_vals {
+                    body.extend(b"\n");
+                    body.extend(&item.data);
+                }
             }
+            ("text/plain; charset=utf-8", body)
         }
+    };
+
+    let mut res = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type);
+    if let Some(next) = next {
+        res = res.header("cursor", next);
     }
+    Ok(res.body(body.into()).expect("Failed to construct response"))
+}
 
-    let mut sep_len = 0;
-    if items.len() > 0 {
-        let sep = match with_vals {
-            true => "\n\n",
-            false => "\n",
+fn range_is_empty(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    // BTreeMap::range panics instead of returning nothing for these
+    match (lower, upper) {
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Excluded(u)) => l >= u,
+        (Bound::Excluded(l), Bound::Included(u)) | (Bound::Included(l), Bound::Included(u)) => {
+            l > u
         }
-        .as_bytes();
-        sep_len = sep.len();
+        _ => false,
+    }
+}
+
+/// `limit` for a page, 100 by default, and at least 1 so there's a page at all
+pub(crate) fn page_limit(limit: Option<i64>) -> Result<usize, AppError> {
+    match limit.unwrap_or(100) {
+        limit if limit < 1 => Err(AppError::CustomCode(
+            anyhow!("limit must be at least 1"),
+            StatusCode::BAD_REQUEST,
+        )),
+        limit => Ok(limit.min(MAX_PAGE_LIMIT) as usize),
     }
+}
+
+pub(crate) fn encode_cursor(key: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+pub(crate) fn decode_cursor(cursor: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .map_err(|_| AppError::CustomCode(anyhow!("invalid cursor"), StatusCode::BAD_REQUEST))
+}
 
-    Ok(items[0..items.len() - sep_len].to_vec().into_response())
+/// Parses a `Range: bytes=...` header into the slice of a value of `len` bytes it asks for.
+/// Ok(None) means serve the whole thing, which is what we do for anything but a single
+/// byte range. Err means the range doesn't overlap the value at all (416).
+fn parse_range(header: &str, len: usize) -> Result<Option<std::ops::Range<usize>>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        // bytes=10-20, inclusive and the end gets clamped
+        (Ok(start), Ok(end)) if start <= end => start..len.min(end.saturating_add(1)),
+        // bytes=10-
+        (Ok(start), Err(_)) if end.is_empty() => start..len,
+        // bytes=-10, the last 10 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => len.saturating_sub(suffix)..len,
+        _ => return Ok(None),
+    };
+    if range.start >= len {
+        return Err(());
+    }
+    Ok(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_range;
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-4", 10), Ok(Some(0..5)));
+        assert_eq!(parse_range("bytes=5-100", 10), Ok(Some(5..10)));
+        assert_eq!(
+            parse_range("bytes=5-18446744073709551615", 10),
+            Ok(Some(5..10))
+        );
+        assert_eq!(parse_range("bytes=7-", 10), Ok(Some(7..10)));
+        assert_eq!(parse_range("bytes=-3", 10), Ok(Some(7..10)));
+        assert_eq!(parse_range("bytes=-30", 10), Ok(Some(0..10)));
+        assert_eq!(parse_range("bytes=10-", 10), Err(()));
+        assert_eq!(parse_range("bytes=0-0", 0), Err(()));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), Ok(None));
+        assert_eq!(parse_range("bytes=5-1", 10), Ok(None));
+        assert_eq!(parse_range("items=0-1", 10), Ok(None));
+    }
 }
@@ -1,2 +1,384 @@
+use std::{borrow::Cow, convert::Infallible};
+
+use anyhow::anyhow;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, MatchedPath, Query},
+    http::{header, request::Parts, response::Builder, HeaderMap, HeaderValue, StatusCode, Uri},
+};
+use base64::{
+    alphabet,
+    engine::{general_purpose::STANDARD, DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{AppError, Item, Meta};
+
+pub mod backup;
+pub mod batch;
+pub mod changes;
+pub mod delete;
 pub mod get;
+pub mod patch;
 pub mod post;
+pub mod query;
+pub mod quota;
+pub mod txn;
+pub mod upload;
+pub mod watch;
+
+/// Values go out as plain strings when they're UTF-8, and as base64 when they're not.
+/// Request bodies use the same shape.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncodedValue {
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<Encoding>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Base64,
+}
+
+impl EncodedValue {
+    pub fn new(data: &[u8]) -> Self {
+        match std::str::from_utf8(data) {
+            Ok(value) => Self {
+                value: value.to_string(),
+                encoding: None,
+            },
+            Err(_) => Self {
+                value: STANDARD.encode(data),
+                encoding: Some(Encoding::Base64),
+            },
+        }
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>, AppError> {
+        match self.encoding {
+            None => Ok(self.value.into_bytes()),
+            Some(Encoding::Base64) => STANDARD.decode(&self.value).map_err(|e| {
+                AppError::CustomCode(
+                    anyhow!("Invalid base64 value: {}", e),
+                    StatusCode::BAD_REQUEST,
+                )
+            }),
+        }
+    }
+}
+
+/// Keys in JSON, the same idea as [`EncodedValue`]: a plain string when they're UTF-8,
+/// base64 with `"key_encoding": "base64"` when they're not. Meant to be flattened into
+/// the struct that holds it.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "JsonKey")]
+pub struct EncodedKey(pub Vec<u8>);
+
+#[derive(Serialize, Deserialize)]
+struct JsonKey {
+    key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_encoding: Option<Encoding>,
+}
+
+impl EncodedKey {
+    pub fn new(key: &[u8]) -> Self {
+        Self(key.to_vec())
+    }
+}
+
+impl Serialize for EncodedKey {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let EncodedValue { value, encoding } = EncodedValue::new(&self.0);
+        JsonKey {
+            key: value,
+            key_encoding: encoding,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl TryFrom<JsonKey> for EncodedKey {
+    type Error = anyhow::Error;
+
+    fn try_from(json: JsonKey) -> anyhow::Result<Self> {
+        match json.key_encoding {
+            None => Ok(Self(json.key.into_bytes())),
+            Some(Encoding::Base64) => STANDARD
+                .decode(&json.key)
+                .map(Self)
+                .map_err(|e| anyhow!("Invalid base64 key: {}", e)),
+        }
+    }
+}
+
+// Base64 in a path has to use the URL-safe alphabet, we don't mind about padding
+const URL_SAFE_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Deserialize, Debug, Default)]
+struct KeyParams {
+    key_encoding: Option<Encoding>,
+}
+
+/// The `:key` path segment as raw bytes. It's percent-decoded, so `%FF%00` is the two
+/// bytes 0xFF 0x00, or with `?key_encoding=base64` it's URL-safe base64.
+#[derive(Debug, Clone)]
+pub struct Key(pub Vec<u8>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Key {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, AppError> {
+        // axum's own path extractors insist on UTF-8, so find which segment of the
+        // route is `:key` and take that segment of the URI as is
+        let matched = MatchedPath::from_request_parts(parts, state)
+            .await
+            .map_err(|e| AppError::CustomCode(anyhow!("{}", e), StatusCode::BAD_REQUEST))?;
+        let raw = matched
+            .as_str()
+            .split('/')
+            .position(|segment| segment == ":key")
+            .and_then(|n| parts.uri.path().split('/').nth(n))
+            .ok_or_else(|| {
+                AppError::CustomCode(anyhow!("No key in the path"), StatusCode::BAD_REQUEST)
+            })?;
+        Ok(Key(decode_key(raw, key_encoding(&parts.uri))?))
+    }
+}
+
+/// The `key_encoding` query param, ignoring everything else in the query
+pub fn key_encoding(uri: &Uri) -> Option<Encoding> {
+    Query::<KeyParams>::try_from_uri(uri)
+        .map(|Query(params)| params.key_encoding)
+        .unwrap_or_default()
+}
+
+/// A key given in a URL, `raw` still percent-encoded
+pub fn decode_key(raw: &str, encoding: Option<Encoding>) -> Result<Vec<u8>, AppError> {
+    unencode_key(
+        percent_encoding::percent_decode_str(raw).collect(),
+        encoding,
+    )
+}
+
+/// A key that's already been percent-decoded, e.g. a query param
+pub fn unencode_key(key: Vec<u8>, encoding: Option<Encoding>) -> Result<Vec<u8>, AppError> {
+    match encoding {
+        None => Ok(key),
+        Some(Encoding::Base64) => URL_SAFE_ANY_PAD.decode(key).map_err(|e| {
+            AppError::CustomCode(
+                anyhow!("Invalid base64 key: {}", e),
+                StatusCode::BAD_REQUEST,
+            )
+        }),
+    }
+}
+
+/// For error messages and logs
+pub fn show_key(key: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(key)
+}
+
+/// The version is all an ETag needs to be. It's strong, a version is only ever one value.
+pub fn etag(version: i64) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{}\"", version)).unwrap()
+}
+
+/// The value of an `If-Match` or `If-None-Match` header
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntityTags {
+    Any,
+    Versions(Vec<i64>),
+}
+
+impl EntityTags {
+    pub fn from_header(headers: &HeaderMap, name: header::HeaderName) -> Option<Self> {
+        let value = headers.get(name)?.to_str().ok()?.trim();
+        if value == "*" {
+            return Some(Self::Any);
+        }
+        // Weak tags compare the same, and a tag that isn't one of ours never matches
+        let versions = value
+            .split(',')
+            .filter_map(|tag| {
+                let tag = tag.trim();
+                let tag = tag.strip_prefix("W/").unwrap_or(tag);
+                tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok()
+            })
+            .collect();
+        Some(Self::Versions(versions))
+    }
+
+    pub fn matches(&self, current: Option<&Item>) -> bool {
+        match self {
+            Self::Any => current.is_some(),
+            Self::Versions(versions) => {
+                current.is_some_and(|item| versions.contains(&item.version))
+            }
+        }
+    }
+}
+
+/// `If-Match` and `If-None-Match` on a write. `If-Match: "<version>"` is the same as
+/// `version`, `If-Match: *` as `ix` and `If-None-Match: *` as `nx`, but a failed one
+/// is a 412 like any HTTP client expects.
+#[derive(Debug, Default)]
+pub struct Preconditions {
+    if_match: Option<EntityTags>,
+    if_none_match: Option<EntityTags>,
+}
+
+impl Preconditions {
+    pub fn is_set(&self) -> bool {
+        self.if_match.is_some() || self.if_none_match.is_some()
+    }
+
+    pub fn check(&self, key: &[u8], current: Option<&Item>) -> Result<(), AppError> {
+        let failed = |header| {
+            Err(AppError::CustomCode(
+                anyhow!("{} doesn't hold for {}", header, show_key(key)),
+                StatusCode::PRECONDITION_FAILED,
+            ))
+        };
+        if let Some(tags) = &self.if_match {
+            if !tags.matches(current) {
+                return failed("If-Match");
+            }
+        }
+        if let Some(tags) = &self.if_none_match {
+            if tags.matches(current) {
+                return failed("If-None-Match");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Preconditions {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        Ok(Self {
+            if_match: EntityTags::from_header(&parts.headers, header::IF_MATCH),
+            if_none_match: EntityTags::from_header(&parts.headers, header::IF_NONE_MATCH),
+        })
+    }
+}
+
+/// Request headers starting with this are kept with the value and sent back on reads
+pub const META_PREFIX: &str = "x-meta-";
+
+/// The `Content-Type` and `x-meta-*` headers of a write, to store with the value
+#[derive(Debug, Default)]
+pub struct WriteMeta(pub Meta);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for WriteMeta {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, AppError> {
+        let mut meta = Meta::default();
+        for (name, value) in &parts.headers {
+            let text = || {
+                value.to_str().map(str::to_string).map_err(|_| {
+                    AppError::CustomCode(
+                        anyhow!("Header {} isn't valid text", name),
+                        StatusCode::BAD_REQUEST,
+                    )
+                })
+            };
+            if name == header::CONTENT_TYPE {
+                meta.content_type = Some(text()?);
+            } else if let Some(field) = name.as_str().strip_prefix(META_PREFIX) {
+                if field.is_empty() {
+                    continue;
+                }
+                // A repeated header is the same as one with the values comma separated
+                let value = text()?;
+                meta.fields
+                    .entry(field.to_string())
+                    .and_modify(|v| {
+                        v.push_str(", ");
+                        v.push_str(&value);
+                    })
+                    .or_insert(value);
+            }
+        }
+        Ok(Self(meta))
+    }
+}
+
+/// Puts back the headers the value was written with
+pub fn meta_headers(mut res: Builder, meta: &Meta) -> Builder {
+    if let Some(content_type) = &meta.content_type {
+        res = res.header(header::CONTENT_TYPE, content_type);
+    }
+    for (field, value) in &meta.fields {
+        res = res.header(format!("{}{}", META_PREFIX, field), value);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_entity_tags() {
+        let mut headers = HeaderMap::new();
+        let tags = |headers: &HeaderMap| EntityTags::from_header(headers, header::IF_NONE_MATCH);
+        assert_eq!(tags(&headers), None);
+
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+        assert_eq!(tags(&headers), Some(EntityTags::Any));
+
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_static(r#""3", W/"5" , "abc", 7"#),
+        );
+        let parsed = tags(&headers).unwrap();
+        assert_eq!(parsed, EntityTags::Versions(vec![3, 5]));
+        let item = |version| Item {
+            version,
+            data: Vec::new(),
+            expires_at: None,
+            meta: Meta::default(),
+        };
+        assert!(parsed.matches(Some(&item(5))));
+        assert!(!parsed.matches(Some(&item(7))));
+        assert!(!parsed.matches(None));
+    }
+
+    #[tokio::test]
+    async fn collects_write_meta() {
+        let (mut parts, _) = axum::http::Request::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Meta-Owner", "bob")
+            .header("x-meta-tag", "a")
+            .header("x-meta-tag", "b")
+            .header("x-other", "ignored")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let Ok(WriteMeta(meta)) = WriteMeta::from_request_parts(&mut parts, &()).await else {
+            panic!("rejected valid headers");
+        };
+        assert_eq!(meta.content_type.as_deref(), Some("application/json"));
+        assert_eq!(
+            meta.fields,
+            [
+                ("owner".to_string(), "bob".to_string()),
+                ("tag".to_string(), "a, b".to_string()),
+            ]
+            .into()
+        );
+    }
+}
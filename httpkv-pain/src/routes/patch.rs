@@ -0,0 +1,164 @@
+use crate::{
+    routes::{etag, show_key, Key, Preconditions},
+    AppError, AppState, Item,
+};
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use anyhow::anyhow;
+use serde::Deserialize;
+use tracing::info;
+use validator::Validate;
+
+#[derive(Deserialize, Debug, Validate)]
+pub struct PatchParams {
+    // Where to write the body, can be at most the current length
+    offset: Option<usize>,
+    // or stick it on the end
+    append: Option<String>,
+    // or add to (or take from) a value that parses as an i64, ignoring the body
+    incr: Option<i64>,
+    decr: Option<i64>,
+
+    #[serde(default, alias = "v")]
+    version: Option<i64>,
+}
+
+enum Edit {
+    At(usize),
+    Append,
+    Add(i64),
+}
+
+/// Edits a value in place under the write lock, keeping its expiry:
+/// - `offset` writes the body over the existing value from there, returns the new length
+/// - `append` writes the body onto the end, creating the key if it's missing, returns the new length
+/// - `incr`/`decr` treat the value as an i64 (missing is 0), returns the new number
+///
+/// The new version comes back as the `ETag`.
+#[tracing::instrument(level = "debug", skip(state, body))]
+pub async fn patch_key(
+    Key(key): Key,
+    State(state): State<AppState>,
+    Query(params): Query<PatchParams>,
+    preconditions: Preconditions,
+    body: Bytes,
+) -> Result<Response, AppError> {
+    params.validate()?;
+
+    let edit = match (
+        params.offset,
+        params.append.is_some(),
+        params.incr,
+        params.decr,
+    ) {
+        (Some(offset), false, None, None) => Edit::At(offset),
+        (None, true, None, None) => Edit::Append,
+        (None, false, Some(n), None) => Edit::Add(n),
+        (None, false, None, Some(n)) => Edit::Add(n.checked_neg().ok_or_else(|| {
+            AppError::CustomCode(anyhow!("decr is out of range"), StatusCode::BAD_REQUEST)
+        })?),
+        _ => {
+            return Err(AppError::CustomCode(
+                anyhow!("Exactly one of offset, append, incr and decr must be given"),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let mut kv = state.kv.write(&[&key]).await;
+    let item = kv.get_live(&key);
+    if item.is_none() {
+        if let Edit::At(_) = edit {
+            return Err(AppError::CustomCode(
+                anyhow!("Key {} doesn't exist", show_key(&key)),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+    }
+    if let Some(version) = params.version {
+        let found = item.map(|item| item.version);
+        if found != Some(version) {
+            return Err(AppError::CustomCode(
+                anyhow!(
+                    "Provided version {} does not match found version {}",
+                    version,
+                    found.map_or("none".to_string(), |v| v.to_string())
+                ),
+                StatusCode::CONFLICT,
+            ));
+        }
+    }
+    preconditions.check(&key, item)?;
+
+    let old = item.map_or(&[][..], |item| item.data.as_slice());
+    let (data, reply) = match edit {
+        Edit::At(_) | Edit::Append => {
+            let offset = match edit {
+                Edit::At(offset) => offset,
+                _ => old.len(),
+            };
+            if offset > old.len() {
+                // No holes
+                return Err(AppError::CustomCode(
+                    anyhow!(
+                        "Offset {} is past the end of the value ({} bytes)",
+                        offset,
+                        old.len()
+                    ),
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                ));
+            }
+            let mut data = old.to_vec();
+            let end = data.len().min(offset + body.len());
+            data.splice(offset..end, body.iter().copied());
+            let len = data.len();
+            (data, len.to_string())
+        }
+        Edit::Add(n) => {
+            let current = match item {
+                Some(_) => std::str::from_utf8(old)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<i64>().ok())
+                    .ok_or_else(|| {
+                        AppError::CustomCode(
+                            anyhow!("Value of {} isn't an integer", show_key(&key)),
+                            StatusCode::CONFLICT,
+                        )
+                    })?,
+                None => 0,
+            };
+            let next = current.checked_add(n).ok_or_else(|| {
+                AppError::CustomCode(
+                    anyhow!("Adding {} to {} overflows", n, current),
+                    StatusCode::CONFLICT,
+                )
+            })?;
+            (next.to_string().into_bytes(), next.to_string())
+        }
+    };
+
+    if let Some(quotas) = state.kv.quotas() {
+        quotas.check_write(&key, kv.get(&key), data.len())?;
+    }
+    let version = kv.next_version();
+    // A patch changes the value, not what it expires at or what it is
+    let (expires_at, meta) = item
+        .map(|item| (item.expires_at, item.meta.clone()))
+        .unwrap_or_default();
+    kv.put(
+        key,
+        Item {
+            version,
+            data,
+            expires_at,
+            meta,
+        },
+    )?;
+    info!(reply = %reply, "patched it");
+
+    Ok(([(header::ETAG, etag(version))], reply).into_response())
+}
@@ -1,11 +1,16 @@
-use std::time::SystemTime;
-
-use crate::{AppError, AppState};
+use crate::{
+    routes::{etag, show_key, Key, Preconditions, WriteMeta},
+    AppError, AppState, Item, Meta,
+};
 use axum::{
     body::Bytes,
-    extract::{Path, Query, State},
+    extract::{Query, State},
+    http::{header, HeaderValue},
+    response::{IntoResponse, Response},
 };
 // use axum_extra::extract::Query;
+use std::ops::Bound;
+
 use anyhow::anyhow;
 use serde::Deserialize;
 use tracing::info;
@@ -21,65 +26,174 @@ pub struct WriteParams {
 
     #[serde(default, alias = "v")]
     version: Option<i64>,
+
+    // Compare and swap, only write if the current value is exactly this
+    expect: Option<String>,
+
+    // Expiry, either seconds from now or a unix timestamp in seconds
+    ttl: Option<u64>,
+    expires_at: Option<i64>,
 }
 
+/// The new version comes back in the `version` header, and as the `ETag`. The
+/// `Content-Type` and any `x-meta-*` headers are kept for reads of the value.
 #[tracing::instrument(level = "debug", skip(state))]
 pub async fn write_key(
-    Path(key): Path<String>,
+    Key(key): Key,
     State(state): State<AppState>,
     Query(params): Query<WriteParams>,
+    preconditions: Preconditions,
+    WriteMeta(meta): WriteMeta,
     body: Bytes,
-) -> Result<String, AppError> {
-    {
-        let val = state.kv.read().await;
-        let val = val.get(&key);
-        match val {
-            Some(item) => {
-                if let Some(_) = params.not_exists {
+) -> Result<Response, AppError> {
+    let version = write_value(&state, key, &params, &preconditions, body.into(), meta).await?;
+    info!("wrote it");
+
+    Ok((
+        [
+            (
+                header::HeaderName::from_static("version"),
+                HeaderValue::from(version),
+            ),
+            (header::ETAG, etag(version)),
+        ],
+        "",
+    )
+        .into_response())
+}
+
+/// Checks the conditions in `params` and the request headers, and writes `data` to
+/// `key` under one write lock. Returns the new version.
+pub(crate) async fn write_value(
+    state: &AppState,
+    key: Vec<u8>,
+    params: &WriteParams,
+    preconditions: &Preconditions,
+    data: Vec<u8>,
+    meta: Meta,
+) -> Result<i64, AppError> {
+    let expires_at = match (params.ttl, params.expires_at) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::CustomCode(
+                anyhow!("Only one of ttl and expires_at can be given"),
+                axum::http::StatusCode::BAD_REQUEST,
+            ));
+        }
+        (Some(ttl), None) => Some(crate::expiry_after(crate::now_millis(), ttl)?),
+        (None, Some(at)) => Some(crate::expiry_at(at)?),
+        (None, None) => None,
+    };
+
+    // Check and write under the same lock so two writers can't both pass an nx check
+    let mut kv = state.kv.write(&[&key]).await;
+    check_conditions(
+        &key,
+        kv.get_live(&key),
+        params.not_exists.is_some(),
+        params.if_exists.is_some(),
+        params.version,
+        params.expect.as_deref().map(str::as_bytes),
+    )?;
+    preconditions.check(&key, kv.get_live(&key))?;
+    if let Some(quotas) = state.kv.quotas() {
+        quotas.check_write(&key, kv.get(&key), data.len())?;
+    }
+
+    // Write the value
+    let version = kv.next_version();
+    kv.put(
+        key,
+        Item {
+            version,
+            data,
+            expires_at,
+            meta,
+        },
+    )?;
+    Ok(version)
+}
+
+/// The nx/ix/version/expect checks for a write to `key`, given what's there now
+pub(crate) fn check_conditions(
+    key: &[u8],
+    current: Option<&Item>,
+    not_exists: bool,
+    if_exists: bool,
+    version: Option<i64>,
+    expect: Option<&[u8]>,
+) -> Result<(), AppError> {
+    if let Some(expect) = expect {
+        if current.map(|item| item.data.as_slice()) != Some(expect) {
+            return Err(AppError::CustomCode(
+                anyhow!(
+                    "Value of {} doesn't match the expected value",
+                    show_key(key)
+                ),
+                axum::http::StatusCode::CONFLICT,
+            ));
+        }
+    }
+    match current {
+        Some(item) => {
+            if not_exists {
+                return Err(AppError::CustomCode(
+                    anyhow!("Key {} exists (nx)", show_key(key)),
+                    axum::http::StatusCode::CONFLICT,
+                ));
+            }
+            if let Some(version) = version {
+                if version != item.version {
                     return Err(AppError::CustomCode(
-                        anyhow!("Key {} exists (nx)", key),
+                        anyhow!(
+                            "Provided version {} does not match found version {}",
+                            version,
+                            item.version
+                        ),
                         axum::http::StatusCode::CONFLICT,
                     ));
                 }
-                if let Some(version) = params.version {
-                    if version != item.version {
-                        return Err(AppError::CustomCode(
-                            anyhow!(
-                                "Provided version {} does not match found version {}",
-                                version,
-                                item.version
-                            ),
-                            axum::http::StatusCode::CONFLICT,
-                        ));
-                    }
-                }
             }
-            None => {
-                if let Some(_) = params.if_exists {
-                    // Check that it exists first
-                    if !state.kv.read().await.contains_key(&key) {
-                        return Err(AppError::CustomCode(
-                            anyhow!("Key {} doesn't exist (ix)", key),
-                            axum::http::StatusCode::CONFLICT,
-                        ));
-                    }
-                }
+        }
+        None => {
+            if if_exists {
+                return Err(AppError::CustomCode(
+                    anyhow!("Key {} doesn't exist (ix)", show_key(key)),
+                    axum::http::StatusCode::CONFLICT,
+                ));
             }
-        };
+        }
     }
+    Ok(())
+}
 
-    // Write the value
-    state.kv.write().await.insert(
-        key,
-        crate::Item {
-            version: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_micros() as i64,
-            data: body.into(),
-        },
-    );
-    info!("wrote it");
+#[derive(Deserialize, Debug)]
+pub struct CompactParams {
+    #[serde(alias = "v")]
+    version: i64,
+}
+
+/// Drops history that's only needed to read at versions before `version`
+#[tracing::instrument(level = "debug", skip(state))]
+pub async fn compact(
+    State(state): State<AppState>,
+    Query(params): Query<CompactParams>,
+) -> Result<String, AppError> {
+    let mut kv = state
+        .kv
+        .write_range((Bound::Unbounded, Bound::Unbounded))
+        .await;
+    if params.version > kv.revision() {
+        return Err(AppError::CustomCode(
+            anyhow!(
+                "Can't compact to {}, latest version is {}",
+                params.version,
+                kv.revision()
+            ),
+            axum::http::StatusCode::BAD_REQUEST,
+        ));
+    }
+    kv.compact(params.version)?;
+    info!(version = params.version, "compacted");
 
     Ok("".to_string())
 }
@@ -0,0 +1,108 @@
+use std::{ops::Bound, sync::Arc};
+
+use crate::{
+    auth::{Grants, Permission},
+    index::Query as Filter,
+    routes::{
+        get::{decode_cursor, encode_cursor, page_limit, ListItem, ListPage},
+        show_key, unencode_key, Encoding,
+    },
+    storage::prefix_end,
+    AppError, AppState,
+};
+use anyhow::anyhow;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use serde::Deserialize;
+use tracing::debug;
+
+#[derive(Deserialize, Debug)]
+pub struct QueryParams {
+    #[serde(default)]
+    prefix: String,
+    key_encoding: Option<Encoding>,
+    // e.g. `status = "failed" AND attempts > 3`
+    #[serde(rename = "where")]
+    filter: String,
+    limit: Option<i64>,
+    // Cursor from the previous page's `next`
+    after: Option<String>,
+    #[serde(default, alias = "vals")]
+    with_vals: Option<String>,
+    meta: Option<String>,
+}
+
+/// Keys under `prefix` whose JSON values match `where`, in key order, a page at a time.
+/// Every field in `where` has to be indexed for a prefix covering `prefix`.
+#[tracing::instrument(level = "debug", skip(state, grants))]
+pub async fn query(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Query(params): Query<QueryParams>,
+) -> Result<Json<ListPage>, AppError> {
+    let prefix = unencode_key(params.prefix.into_bytes(), params.key_encoding)?;
+    let with_vals = params.with_vals.is_some();
+    if let Some(Extension(grants)) = grants {
+        grants.check(Permission::List, &prefix)?;
+        if with_vals {
+            grants.check(Permission::Read, &prefix)?;
+        }
+    }
+    let bad_request = |e: anyhow::Error| AppError::CustomCode(e, StatusCode::BAD_REQUEST);
+    let filter = Filter::parse(&params.filter).map_err(bad_request)?;
+    let indexes = state
+        .kv
+        .indexes()
+        .ok_or_else(|| bad_request(anyhow!("No indexes are configured")))?;
+    indexes.covers(&prefix, &filter).map_err(|field| {
+        bad_request(anyhow!(
+            "{} isn't indexed for keys under {}",
+            field,
+            show_key(&prefix)
+        ))
+    })?;
+    let after = params.after.as_deref().map(decode_cursor).transpose()?;
+    let limit = page_limit(params.limit)?;
+
+    // The indexes only agree with the keys we have locked, so lock first
+    let prefix_end = prefix_end(&prefix);
+    let range = (
+        Bound::Included(prefix.as_slice()),
+        match &prefix_end {
+            Some(end) => Bound::Excluded(end.as_slice()),
+            None => Bound::Unbounded,
+        },
+    );
+    let kv = state.kv.read_range(range).await;
+
+    // Grab one extra so we know if there's another page
+    let mut page = Vec::new();
+    let mut scanned = 0;
+    indexes
+        .candidates(&prefix, filter.driver(), after.as_deref(), |key| {
+            scanned += 1;
+            if let Some(item) = kv.get_live(key).filter(|item| filter.matches(item)) {
+                page.push((key.to_vec(), item));
+            }
+            page.len() <= limit
+        })
+        .expect("covers checked every field");
+    debug!(scanned = scanned, "queried");
+    let next = if page.len() > limit {
+        page.truncate(limit);
+        page.last().map(|(key, _)| encode_cursor(key))
+    } else {
+        None
+    };
+
+    Ok(Json(ListPage {
+        items: page
+            .into_iter()
+            .map(|(key, item)| ListItem::new(&key, item, with_vals, params.meta.is_some()))
+            .collect(),
+        next,
+    }))
+}
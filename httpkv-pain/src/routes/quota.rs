@@ -0,0 +1,15 @@
+use axum::{extract::State, Json};
+
+use crate::{quota::Usage, AppState};
+
+/// Each configured prefix's limits next to what it's using now
+#[tracing::instrument(level = "debug", skip(state))]
+pub async fn usage(State(state): State<AppState>) -> Json<Vec<Usage>> {
+    Json(
+        state
+            .kv
+            .quotas()
+            .map(|quotas| quotas.usage())
+            .unwrap_or_default(),
+    )
+}
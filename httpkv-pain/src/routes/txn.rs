@@ -0,0 +1,263 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    auth::{Grants, Permission},
+    storage::{Op, WriteView},
+    routes::{EncodedKey, EncodedValue, Encoding},
+    AppError, AppState, Item, Meta,
+};
+use axum::{extract::State, Extension, Json};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+/// etcd style transaction: if every compare holds run `success`, otherwise run `failure`.
+/// The compares and the ops all happen under one write lock, and the writes are
+/// applied to the storage as a single batch.
+#[derive(Deserialize, Debug)]
+pub struct TxnRequest {
+    #[serde(default)]
+    compare: Vec<Compare>,
+    #[serde(default)]
+    success: Vec<TxnOp>,
+    #[serde(default)]
+    failure: Vec<TxnOp>,
+}
+
+/// Every provided condition has to hold for the compare to pass
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "JsonCompare")]
+pub struct Compare {
+    key: EncodedKey,
+    exists: Option<bool>,
+    version: Option<i64>,
+    value: Option<Vec<u8>>,
+}
+
+/// A compare as sent, its `value` and `encoding` the same as a put's
+#[derive(Deserialize)]
+struct JsonCompare {
+    #[serde(flatten)]
+    key: EncodedKey,
+    exists: Option<bool>,
+    version: Option<i64>,
+    value: Option<String>,
+    encoding: Option<Encoding>,
+}
+
+impl TryFrom<JsonCompare> for Compare {
+    type Error = anyhow::Error;
+
+    fn try_from(json: JsonCompare) -> anyhow::Result<Self> {
+        let value = json
+            .value
+            .map(|value| {
+                EncodedValue {
+                    value,
+                    encoding: json.encoding,
+                }
+                .into_bytes()
+            })
+            .transpose()
+            .map_err(|e| match e {
+                AppError::CustomCode(e, _) | AppError::Anyhow(e) => e,
+            })?;
+        Ok(Self {
+            key: json.key,
+            exists: json.exists,
+            version: json.version,
+            value,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TxnOp {
+    Put {
+        #[serde(flatten)]
+        key: EncodedKey,
+        #[serde(flatten)]
+        value: EncodedValue,
+        // Seconds from now
+        ttl: Option<u64>,
+    },
+    Delete {
+        #[serde(flatten)]
+        key: EncodedKey,
+    },
+    Get {
+        #[serde(flatten)]
+        key: EncodedKey,
+    },
+}
+
+#[derive(Serialize, Debug)]
+pub struct TxnResponse {
+    succeeded: bool,
+    results: Vec<TxnOpResult>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum TxnOpResult {
+    Put { version: i64 },
+    Delete { deleted: bool },
+    Get(Option<GetResult>),
+}
+
+#[derive(Serialize, Debug)]
+pub struct GetResult {
+    #[serde(flatten)]
+    key: EncodedKey,
+    #[serde(flatten)]
+    value: EncodedValue,
+    version: i64,
+}
+
+#[tracing::instrument(level = "debug", skip(state, grants))]
+pub async fn txn(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Json(req): Json<TxnRequest>,
+) -> Result<Json<TxnResponse>, AppError> {
+    // Check every key up front, whichever branch runs, so a txn can't be used to probe
+    if let Some(Extension(grants)) = grants {
+        for cmp in &req.compare {
+            grants.check(Permission::Read, &cmp.key.0)?;
+        }
+        for op in req.success.iter().chain(&req.failure) {
+            match op {
+                TxnOp::Put { key, .. } | TxnOp::Delete { key } => {
+                    grants.check(Permission::Write, &key.0)?
+                }
+                TxnOp::Get { key } => grants.check(Permission::Read, &key.0)?,
+            }
+        }
+    }
+
+    // Both branches' keys, we don't know which will run yet
+    let keys: Vec<&[u8]> = req
+        .compare
+        .iter()
+        .map(|cmp| &cmp.key)
+        .chain(req.success.iter().chain(&req.failure).map(TxnOp::key))
+        .map(|key| key.0.as_slice())
+        .collect();
+    let mut kv = state.kv.write(&keys).await;
+
+    let succeeded = req
+        .compare
+        .iter()
+        .all(|cmp| compare_holds(kv.get_live(&cmp.key.0), cmp));
+    let ops = if succeeded { req.success } else { req.failure };
+
+    // Writes are staged so later ops in the txn see earlier ones, then applied in one batch
+    let version = kv.next_version();
+    let mut staged: HashMap<Vec<u8>, Option<Item>> = HashMap::new();
+    let mut quotas = state.kv.quotas().map(|quotas| quotas.batch());
+    let mut results = Vec::with_capacity(ops.len());
+    for op in ops {
+        match op {
+            TxnOp::Put { key, value, ttl } => {
+                let data = value.into_bytes()?;
+                let expires_at = ttl
+                    .map(|ttl| crate::expiry_after(crate::now_millis(), ttl))
+                    .transpose()?;
+                if let Some(quotas) = &mut quotas {
+                    let old = stored(&kv, &staged, &key.0);
+                    quotas.check_write(&key.0, old, data.len())?;
+                }
+                staged.insert(
+                    key.0,
+                    Some(Item {
+                        version,
+                        data,
+                        expires_at,
+                        meta: Meta::default(),
+                    }),
+                );
+                results.push(TxnOpResult::Put { version });
+            }
+            TxnOp::Delete { key } => {
+                if let Some(quotas) = &mut quotas {
+                    quotas.delete(&key.0, stored(&kv, &staged, &key.0));
+                }
+                let deleted = current(&kv, &staged, &key.0).is_some();
+                staged.insert(key.0, None);
+                results.push(TxnOpResult::Delete { deleted });
+            }
+            TxnOp::Get { key } => {
+                let found = current(&kv, &staged, &key.0).map(|item| GetResult {
+                    key,
+                    value: EncodedValue::new(&item.data),
+                    version: item.version,
+                });
+                results.push(TxnOpResult::Get(found));
+            }
+        }
+    }
+
+    let writes: Vec<Op> = staged
+        .into_iter()
+        .map(|(key, item)| match item {
+            Some(item) => Op::Put { key, item },
+            None => Op::Delete { key, version },
+        })
+        .collect();
+    if !writes.is_empty() {
+        kv.apply(writes)?;
+    }
+    info!(succeeded = succeeded, ops = results.len(), "ran txn");
+
+    Ok(Json(TxnResponse { succeeded, results }))
+}
+
+impl TxnOp {
+    fn key(&self) -> &EncodedKey {
+        match self {
+            TxnOp::Put { key, .. } | TxnOp::Delete { key } | TxnOp::Get { key } => key,
+        }
+    }
+}
+
+fn compare_holds(item: Option<&Item>, cmp: &Compare) -> bool {
+    if let Some(exists) = cmp.exists {
+        if exists != item.is_some() {
+            return false;
+        }
+    }
+    if let Some(version) = cmp.version {
+        if item.map(|i| i.version) != Some(version) {
+            return false;
+        }
+    }
+    if let Some(value) = &cmp.value {
+        if item.map(|i| &i.data) != Some(value) {
+            return false;
+        }
+    }
+    true
+}
+
+fn current<'a>(
+    kv: &'a WriteView,
+    staged: &'a HashMap<Vec<u8>, Option<Item>>,
+    key: &[u8],
+) -> Option<&'a Item> {
+    match staged.get(key) {
+        Some(staged) => staged.as_ref(),
+        None => kv.get_live(key),
+    }
+}
+
+/// Like [`current`], but including an expired key the reaper hasn't got to yet
+fn stored<'a>(
+    kv: &'a WriteView,
+    staged: &'a HashMap<Vec<u8>, Option<Item>>,
+    key: &[u8],
+) -> Option<&'a Item> {
+    match staged.get(key) {
+        Some(staged) => staged.as_ref(),
+        None => kv.get(key),
+    }
+}
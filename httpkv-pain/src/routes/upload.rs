@@ -0,0 +1,185 @@
+use std::sync::Arc;
+
+use crate::{
+    auth::{Grants, Permission},
+    routes::{
+        post::{write_value, WriteParams},
+        show_key, unencode_key, EncodedKey, Encoding, Preconditions, WriteMeta,
+    },
+    upload::{Session, MAX_CHUNKS},
+    AppError, AppState,
+};
+use anyhow::anyhow;
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+#[derive(Deserialize, Debug)]
+pub struct StartParams {
+    key: String,
+    key_encoding: Option<Encoding>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadStatus {
+    id: String,
+    #[serde(flatten)]
+    key: EncodedKey,
+    /// Chunk numbers we have
+    chunks: Vec<u32>,
+    /// Gaps below the highest chunk, these need sending before a commit
+    missing: Vec<u32>,
+    bytes: usize,
+    /// Unix millis, pushed out by every chunk
+    expires_at: i64,
+}
+
+impl UploadStatus {
+    fn new(id: String, session: &Session) -> Self {
+        Self {
+            id,
+            key: EncodedKey::new(&session.key),
+            chunks: session.chunks.keys().copied().collect(),
+            missing: session.missing(),
+            bytes: session.bytes,
+            expires_at: session.expires_at,
+        }
+    }
+}
+
+/// Starts an upload session for `key`. The value isn't written until it's committed.
+#[tracing::instrument(level = "debug", skip(state, grants))]
+pub async fn start_upload(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Query(params): Query<StartParams>,
+) -> Result<(StatusCode, Json<UploadStatus>), AppError> {
+    let key = unencode_key(params.key.into_bytes(), params.key_encoding)?;
+    if let Some(Extension(grants)) = grants {
+        grants.check(Permission::Write, &key)?;
+    }
+    let (id, session) = state.uploads.start(key).ok_or_else(|| {
+        AppError::CustomCode(
+            anyhow!("Too many uploads in progress"),
+            StatusCode::TOO_MANY_REQUESTS,
+        )
+    })?;
+    info!(id = %id, key = %show_key(&session.key), "started upload");
+    Ok((StatusCode::CREATED, Json(UploadStatus::new(id, &session))))
+}
+
+/// What's been received so far, for resuming after a dropped connection
+#[tracing::instrument(level = "debug", skip(state, grants))]
+pub async fn upload_status(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Path(id): Path<String>,
+) -> Result<Json<UploadStatus>, AppError> {
+    let session = find(&state, grants, &id)?;
+    Ok(Json(UploadStatus::new(id, &session)))
+}
+
+/// Stores chunk `n` (from 0, below MAX_CHUNKS), replacing it if it was already sent.
+/// Returns the total bytes received.
+#[tracing::instrument(level = "debug", skip(state, grants, body), fields(len = body.len()))]
+pub async fn put_chunk(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Path((id, n)): Path<(String, u32)>,
+    body: Bytes,
+) -> Result<String, AppError> {
+    find(&state, grants, &id)?;
+    if n >= MAX_CHUNKS {
+        return Err(AppError::CustomCode(
+            anyhow!("Chunks are numbered from 0 to {}", MAX_CHUNKS - 1),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    let bytes = state
+        .uploads
+        .put_chunk(&id, n, body)
+        .ok_or_else(|| not_found(&id))??;
+    Ok(bytes.to_string())
+}
+
+/// Writes the chunks, in order, as the new value of the key, taking the same
+/// conditions and expiry params as a plain write. The session is gone after a
+/// successful commit, and left alone for another try after a failed one.
+#[tracing::instrument(level = "debug", skip(state, grants))]
+pub async fn commit_upload(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Path(id): Path<String>,
+    Query(params): Query<WriteParams>,
+    preconditions: Preconditions,
+    WriteMeta(meta): WriteMeta,
+) -> Result<String, AppError> {
+    find(&state, grants, &id)?;
+    // Take it out so a concurrent commit or chunk can't race us
+    let session = state.uploads.remove(&id).ok_or_else(|| not_found(&id))?;
+    let missing = session.missing();
+    if !missing.is_empty() {
+        let err = AppError::CustomCode(
+            anyhow!("Upload {} is missing chunks {:?}", id, missing),
+            StatusCode::BAD_REQUEST,
+        );
+        state.uploads.restore(id, session);
+        return Err(err);
+    }
+
+    match write_value(
+        &state,
+        session.key.clone(),
+        &params,
+        &preconditions,
+        session.assemble(),
+        meta,
+    )
+    .await
+    {
+        Ok(version) => {
+            info!(id = %id, key = %show_key(&session.key), bytes = session.bytes, version = version, "committed upload");
+            Ok("".to_string())
+        }
+        Err(e) => {
+            state.uploads.restore(id, session);
+            Err(e)
+        }
+    }
+}
+
+#[tracing::instrument(level = "debug", skip(state, grants))]
+pub async fn abort_upload(
+    State(state): State<AppState>,
+    grants: Option<Extension<Arc<Grants>>>,
+    Path(id): Path<String>,
+) -> Result<String, AppError> {
+    find(&state, grants, &id)?;
+    state.uploads.remove(&id);
+    Ok("".to_string())
+}
+
+/// The session, if it exists and the token can write its key
+fn find(
+    state: &AppState,
+    grants: Option<Extension<Arc<Grants>>>,
+    id: &str,
+) -> Result<Session, AppError> {
+    let session = state.uploads.get(id).ok_or_else(|| not_found(id))?;
+    if let Some(Extension(grants)) = grants {
+        grants.check(Permission::Write, &session.key)?;
+    }
+    Ok(session)
+}
+
+fn not_found(id: &str) -> AppError {
+    AppError::CustomCode(
+        anyhow!("Upload {} doesn't exist or has expired", id),
+        StatusCode::NOT_FOUND,
+    )
+}
@@ -0,0 +1,114 @@
+use std::convert::Infallible;
+
+use crate::{
+    routes::{EncodedKey, EncodedValue},
+    storage::Compacted,
+    watch::{Event, Subscription},
+    AppError, AppState,
+};
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    http::{header, StatusCode},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+#[derive(Serialize)]
+struct WatchEvent<'a> {
+    #[serde(flatten)]
+    event: Event<'a>,
+    #[serde(flatten)]
+    key: EncodedKey,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    value: Option<EncodedValue>,
+}
+
+/// Streams every put and delete on `key` (or every key starting with it) as NDJSON,
+/// or as SSE if the client asks for `text/event-stream`. `from` replays everything
+/// after that version first, so a client can resume without missing events.
+pub async fn watch_items(
+    state: AppState,
+    key: Vec<u8>,
+    prefix: bool,
+    from: Option<i64>,
+    with_vals: bool,
+    sse: bool,
+) -> Result<Response, AppError> {
+    let Subscription { replay, rx } = state.watcher.subscribe(from).map_err(|Compacted { oldest }| {
+        AppError::CustomCode(
+            anyhow!(
+                "Version {} is too old to resume from, oldest available is {}",
+                from.unwrap_or_default(),
+                oldest
+            ),
+            StatusCode::GONE,
+        )
+    })?;
+
+    let live = stream::unfold(rx, |mut rx| async move {
+        match rx.recv().await {
+            Ok(batch) => Some((batch, rx)),
+            Err(RecvError::Lagged(skipped)) => {
+                // The client can reconnect with the last version it saw
+                warn!(skipped = skipped, "Watcher fell behind, closing stream");
+                None
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+    let from = from.unwrap_or(i64::MIN);
+    let events = stream::iter(replay).chain(live).flat_map(move |batch| {
+        let lines: Vec<String> = batch
+            .events()
+            .filter(|event| {
+                // A client resuming mid-batch has already seen the start of it
+                event.version > from
+                    && if prefix {
+                        event.key.starts_with(&key)
+                    } else {
+                        event.key == key.as_slice()
+                    }
+            })
+            .map(|event| encode_event(event, with_vals))
+            .collect();
+        stream::iter(lines)
+    });
+
+    if sse {
+        Ok(Sse::new(events.map(|line| Ok::<_, Infallible>(sse::Event::default().data(line))))
+            .keep_alive(KeepAlive::default())
+            .into_response())
+    } else {
+        Ok(ndjson_response(events.map(|line| Ok::<_, Infallible>(line + "\n"))))
+    }
+}
+
+fn encode_event(event: Event, with_vals: bool) -> String {
+    let value = match (event.data, with_vals) {
+        (Some(data), true) => Some(EncodedValue::new(data)),
+        _ => None,
+    };
+    serde_json::to_string(&WatchEvent {
+        key: EncodedKey::new(event.key),
+        event,
+        value,
+    })
+    .expect("Failed to encode event")
+}
+
+fn ndjson_response(
+    body: impl Stream<Item = Result<String, Infallible>> + Send + 'static,
+) -> Response {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(body))
+        .expect("Failed to construct response")
+}
@@ -0,0 +1,499 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use super::{keyspace::Merged, v1, Format, Keyspace, Op, Storage};
+
+const WAL_FILE: &str = "wal";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+
+// Both files start with one of these. Files from before they did are `Format::V1`.
+const WAL_MAGIC: &[u8; 8] = b"HTTPKVW2";
+const SNAPSHOT_MAGIC: &[u8; 8] = b"HTTPKVS2";
+
+// The WAL starts with [magic][generation u64 LE], then each record is
+// [len u32 LE][crc32 u32 LE][bincode Vec<Op>]
+const WAL_HEADER_LEN: usize = WAL_MAGIC.len() + 8;
+const RECORD_HEADER_LEN: usize = 8;
+
+/// How far into the WAL a snapshot goes: everything before `offset` in the WAL of this
+/// `generation`, and all of every earlier one. Each checkpoint starts a new generation.
+/// A `Format::V1` WAL, which has no header, is generation 0.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct WalPosition {
+    generation: u64,
+    offset: u64,
+}
+
+/// A write-ahead log and a snapshot on disk.
+///
+/// Every batch is appended to the WAL and fsynced before it touches the keyspace.
+/// `checkpoint` writes the whole keyspace to a new snapshot and truncates the WAL.
+/// On open we load the snapshot and replay the WAL over it from where the snapshot
+/// left off, dropping a torn record at the tail if we were killed mid-write. A data
+/// dir in an older format is rewritten in the current one as soon as it's loaded.
+#[derive(Debug)]
+pub struct DiskStorage {
+    dir: PathBuf,
+    // What we loaded, until `load` takes it
+    ks: Keyspace,
+    wal: File,
+    generation: u64,
+    wal_len: u64,
+}
+
+impl DiskStorage {
+    pub fn open(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+
+        let (mut ks, covered, snapshot_format) = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(buf) => decode_snapshot(&buf).context("reading snapshot")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                (Keyspace::default(), None, Format::V2)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        ks.recount();
+
+        let mut wal = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))?;
+        let mut buf = Vec::new();
+        wal.read_to_end(&mut buf)?;
+
+        let mut fresh = false;
+        let (mut generation, wal_format, start) = match wal_generation(&buf) {
+            Some(generation) => (generation, Format::V2, WAL_HEADER_LEN),
+            // New, or we died before the header was down
+            None if buf.len() < WAL_HEADER_LEN => {
+                fresh = true;
+                buf.clear();
+                (covered.map_or(1, |pos| pos.generation + 1), Format::V2, 0)
+            }
+            None => (0, Format::V1, 0),
+        };
+        let mut offset = match covered {
+            Some(pos) if pos.generation == generation => {
+                let offset = pos.offset as usize;
+                if offset > buf.len() {
+                    bail!("snapshot goes {} bytes into a WAL of {}", offset, buf.len());
+                }
+                offset.max(start)
+            }
+            Some(pos) if pos.generation > generation => bail!(
+                "WAL generation {} is older than the snapshot's {}",
+                generation,
+                pos.generation
+            ),
+            _ => start,
+        };
+
+        let mut replayed = 0;
+        // A record that fails its checksum can only be the torn tail, we fsync every one
+        // before the next. One that passes but doesn't decode is something we don't
+        // understand, and dropping it would lose data.
+        while let Ok(Some((payload, len))) = record_payload(&buf[offset..]) {
+            let ops = decode_ops(payload, wal_format)
+                .with_context(|| format!("decoding WAL record at offset {}", offset))?;
+            offset += len;
+            if snapshot_format == Format::V1 {
+                // These don't say where they left off, so if we died between writing
+                // one and truncating the WAL, skip what it has by version. A reset
+                // can go back in versions, so it's always replayed.
+                let reset = ops.iter().any(|op| matches!(op, Op::Reset { .. }));
+                let version = ops.iter().filter_map(Op::version).max();
+                if !reset && version.is_some_and(|v| v <= ks.revision()) {
+                    continue;
+                }
+            }
+            ks.apply(ops);
+            replayed += 1;
+        }
+        if offset < buf.len() {
+            warn!(
+                valid = offset,
+                total = buf.len(),
+                "Truncating torn record at the end of the WAL"
+            );
+            wal.set_len(offset as u64)?;
+            wal.sync_all()?;
+        }
+        if snapshot_format == Format::V1 || wal_format == Format::V1 {
+            info!(dir = %dir.display(), "Upgrading data dir to the current format");
+            let covered = WalPosition {
+                generation,
+                offset: offset as u64,
+            };
+            write_snapshot(
+                &dir,
+                covered,
+                &Merged {
+                    parts: &[&ks],
+                    revision: ks.revision(),
+                },
+            )?;
+            generation += 1;
+            fresh = true;
+        }
+        if fresh {
+            wal.set_len(0)?;
+            wal.write_all(&wal_header(generation))?;
+            wal.sync_all()?;
+            offset = WAL_HEADER_LEN;
+        }
+
+        info!(
+            dir = %dir.display(),
+            keys = ks.len(),
+            revision = ks.revision(),
+            generation = generation,
+            replayed = replayed,
+            "Opened disk storage"
+        );
+        Ok(Self {
+            dir,
+            ks,
+            wal,
+            generation,
+            wal_len: offset as u64,
+        })
+    }
+}
+
+fn wal_header(generation: u64) -> Vec<u8> {
+    let mut header = WAL_MAGIC.to_vec();
+    header.extend(generation.to_le_bytes());
+    header
+}
+
+/// None if the WAL doesn't start with a whole header
+fn wal_generation(buf: &[u8]) -> Option<u64> {
+    let generation = buf.strip_prefix(WAL_MAGIC)?.get(..8)?;
+    Some(u64::from_le_bytes(generation.try_into().unwrap()))
+}
+
+impl Storage for DiskStorage {
+    fn load(&mut self) -> Keyspace {
+        std::mem::take(&mut self.ks)
+    }
+
+    fn append(&mut self, ops: &[Op]) -> anyhow::Result<()> {
+        let record = encode_record(ops, Format::V2)?;
+        if let Err(e) = self.wal.write_all(&record).and_then(|_| self.wal.sync_data()) {
+            // Don't leave a partial record behind for the next write to land after
+            self.wal.set_len(self.wal_len)?;
+            return Err(e.into());
+        }
+        self.wal_len += record.len() as u64;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, parts: &[&Keyspace], revision: i64) -> anyhow::Result<()> {
+        if self.wal_len == WAL_HEADER_LEN as u64 {
+            return Ok(());
+        }
+
+        let covered = WalPosition {
+            generation: self.generation,
+            offset: self.wal_len,
+        };
+        write_snapshot(&self.dir, covered, &Merged { parts, revision })?;
+
+        // If we die before this, `open` replays from where the snapshot left off
+        self.generation += 1;
+        self.wal.set_len(0)?;
+        self.wal.write_all(&wal_header(self.generation))?;
+        self.wal.sync_all()?;
+        debug!(
+            keys = parts.iter().map(|ks| ks.len()).sum::<usize>(),
+            wal_bytes = self.wal_len,
+            "Checkpointed WAL into snapshot"
+        );
+        self.wal_len = WAL_HEADER_LEN as u64;
+        Ok(())
+    }
+}
+
+/// Replaces the snapshot, atomically
+fn write_snapshot(dir: &Path, covered: WalPosition, ks: &Merged) -> anyhow::Result<()> {
+    let tmp_path = dir.join(SNAPSHOT_TMP_FILE);
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(&encode_snapshot(covered, ks)?)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, dir.join(SNAPSHOT_FILE))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+pub(crate) fn encode_record(ops: &[Op], format: Format) -> anyhow::Result<Vec<u8>> {
+    let payload = match format {
+        Format::V1 => bincode::serialize(&ops.iter().map(v1::Op::from).collect::<Vec<_>>())?,
+        Format::V2 => bincode::serialize(ops)?,
+    };
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend((payload.len() as u32).to_le_bytes());
+    record.extend(crc32fast::hash(&payload).to_le_bytes());
+    record.extend(payload);
+    Ok(record)
+}
+
+/// Returns the ops and the total record length, None if the buffer doesn't hold a
+/// whole record yet, or an error if the record is corrupt.
+pub(crate) fn decode_record(
+    buf: &[u8],
+    format: Format,
+) -> anyhow::Result<Option<(Vec<Op>, usize)>> {
+    let Some((payload, len)) = record_payload(buf)? else {
+        return Ok(None);
+    };
+    Ok(Some((decode_ops(payload, format)?, len)))
+}
+
+/// Like [`decode_record`], but stops at checking the checksum
+fn record_payload(buf: &[u8]) -> anyhow::Result<Option<(&[u8], usize)>> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(buf[4..8].try_into().unwrap());
+    let Some(payload) = buf.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len) else {
+        return Ok(None);
+    };
+    if crc32fast::hash(payload) != crc {
+        return Err(anyhow!("record checksum mismatch"));
+    }
+    Ok(Some((payload, RECORD_HEADER_LEN + len)))
+}
+
+fn decode_ops(payload: &[u8], format: Format) -> anyhow::Result<Vec<Op>> {
+    Ok(match format {
+        Format::V1 => bincode::deserialize::<Vec<v1::Op>>(payload)?
+            .into_iter()
+            .map(Into::into)
+            .collect(),
+        Format::V2 => bincode::deserialize(payload)?,
+    })
+}
+
+// [magic][crc32 u32 LE][bincode (WalPosition, Keyspace)]
+fn encode_snapshot(covered: WalPosition, ks: &Merged) -> anyhow::Result<Vec<u8>> {
+    let payload = bincode::serialize(&(covered, ks))?;
+    let mut buf = Vec::with_capacity(SNAPSHOT_MAGIC.len() + 4 + payload.len());
+    buf.extend(SNAPSHOT_MAGIC);
+    buf.extend(crc32fast::hash(&payload).to_le_bytes());
+    buf.extend(payload);
+    Ok(buf)
+}
+
+/// V1 snapshots don't say how far into the WAL they go
+fn decode_snapshot(buf: &[u8]) -> anyhow::Result<(Keyspace, Option<WalPosition>, Format)> {
+    let (buf, format) = match buf.strip_prefix(SNAPSHOT_MAGIC) {
+        Some(rest) => (rest, Format::V2),
+        None => (buf, Format::V1),
+    };
+    if buf.len() < 4 {
+        return Err(anyhow!("snapshot too short"));
+    }
+    let crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    if crc32fast::hash(&buf[4..]) != crc {
+        return Err(anyhow!("snapshot checksum mismatch"));
+    }
+    Ok(match format {
+        Format::V1 => {
+            let ks = bincode::deserialize::<v1::Keyspace>(&buf[4..])?;
+            (ks.into(), None, format)
+        }
+        Format::V2 => {
+            let (covered, ks) = bincode::deserialize(&buf[4..])?;
+            (ks, Some(covered), format)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use super::*;
+    use crate::{storage::Sharded, Item};
+
+    fn item(version: i64, data: &str) -> Item {
+        Item {
+            version,
+            data: data.as_bytes().to_vec(),
+            expires_at: None,
+            meta: Default::default(),
+        }
+    }
+
+    fn open(dir: &Path) -> anyhow::Result<Sharded> {
+        // Small partitions, so the snapshot has to stitch several together
+        Ok(Sharded::new(Box::new(DiskStorage::open(dir)?), 2))
+    }
+
+    #[tokio::test]
+    async fn survives_reopen_and_checkpoint() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let everything = (Bound::Unbounded, Bound::Unbounded);
+        {
+            let s = open(dir.path())?;
+            let mut kv = s.write_range(everything).await;
+            kv.put(b"a".to_vec(), item(1, "one"))?;
+            kv.put(b"b".to_vec(), item(2, "two"))?;
+            kv.put(b"d".to_vec(), item(3, "four"))?;
+            drop(kv);
+            s.checkpoint().await?;
+            let mut kv = s.write_range(everything).await;
+            kv.put(b"c".to_vec(), item(4, "three"))?;
+            kv.delete(b"a".to_vec())?;
+        }
+
+        let s = open(dir.path())?;
+        assert!(s.partition_count() > 1);
+        let kv = s.read_range(everything).await;
+        assert!(kv.get(b"a").is_none());
+        assert_eq!(kv.get(b"b").unwrap().data, b"two");
+        assert_eq!(kv.get(b"c").unwrap().version, 4);
+        assert_eq!(kv.len(), 3);
+        assert_eq!(kv.revision(), 5);
+        assert_eq!(kv.get_at(b"a", 4).unwrap().unwrap().data, b"one");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replays_from_where_the_snapshot_left_off() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let everything = (Bound::Unbounded, Bound::Unbounded);
+        let wal_path = dir.path().join(WAL_FILE);
+        {
+            let s = open(dir.path())?;
+            let mut kv = s.write_range(everything).await;
+            kv.put(b"a".to_vec(), item(1, "one"))?;
+            kv.put(b"a".to_vec(), item(2, "two"))?;
+            kv.put(b"b".to_vec(), item(3, "three"))?;
+            drop(kv);
+
+            // As if we died between writing the snapshot and truncating the WAL
+            let wal = fs::read(&wal_path)?;
+            s.checkpoint().await?;
+            fs::write(&wal_path, wal)?;
+        }
+        {
+            let s = open(dir.path())?;
+            let kv = s.read_range(everything).await;
+            assert_eq!(kv.history(b"a").len(), 1);
+            assert_eq!(kv.len(), 2);
+            drop(kv);
+
+            // A follower resyncing from a leader that's behind it
+            s.write_range(everything).await.apply(vec![
+                Op::Reset { revision: 2 },
+                Op::Put {
+                    key: b"c".to_vec(),
+                    item: item(2, "two"),
+                },
+            ])?;
+        }
+
+        let s = open(dir.path())?;
+        let kv = s.read_range(everything).await;
+        assert!(kv.get(b"a").is_none());
+        assert!(kv.get(b"b").is_none());
+        assert_eq!(kv.get(b"c").unwrap().data, b"two");
+        assert_eq!(kv.revision(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upgrades_a_v1_data_dir() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let everything = (Bound::Unbounded, Bound::Unbounded);
+        let ks = v1::Keyspace {
+            map: [(b"a".to_vec(), v1::Item::from(&item(1, "one")))].into(),
+            history: Default::default(),
+            revision: 1,
+            compacted: 0,
+        };
+        let payload = bincode::serialize(&ks)?;
+        let mut snapshot = crc32fast::hash(&payload).to_le_bytes().to_vec();
+        snapshot.extend(payload);
+        fs::write(dir.path().join(SNAPSHOT_FILE), snapshot)?;
+        let put = Op::Put {
+            key: b"b".to_vec(),
+            item: item(2, "two"),
+        };
+        fs::write(
+            dir.path().join(WAL_FILE),
+            encode_record(&[put], Format::V1)?,
+        )?;
+
+        // The second time from what the first one rewrote
+        for _ in 0..2 {
+            let s = open(dir.path())?;
+            let kv = s.read_range(everything).await;
+            assert_eq!(kv.get(b"a").unwrap().data, b"one");
+            assert_eq!(kv.get(b"b").unwrap().version, 2);
+        }
+        assert!(fs::read(dir.path().join(SNAPSHOT_FILE))?.starts_with(SNAPSHOT_MAGIC));
+
+        // A record that passes its checksum but doesn't decode isn't a torn tail
+        let garbage = b"not ops";
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(WAL_FILE))?;
+        wal.write_all(&(garbage.len() as u32).to_le_bytes())?;
+        wal.write_all(&crc32fast::hash(garbage).to_le_bytes())?;
+        wal.write_all(garbage)?;
+        drop(wal);
+        assert!(DiskStorage::open(dir.path()).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_torn_wal_tail() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let everything = (Bound::Unbounded, Bound::Unbounded);
+        {
+            let s = open(dir.path())?;
+            s.write(&[b"a"]).await.put(b"a".to_vec(), item(1, "one"))?;
+        }
+
+        // Simulate getting killed halfway through the second record
+        let record = encode_record(
+            &[Op::Put {
+                key: b"b".to_vec(),
+                item: item(2, "two"),
+            }],
+            Format::V2,
+        )?;
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(WAL_FILE))?;
+        wal.write_all(&record[..record.len() / 2])?;
+        drop(wal);
+
+        let s = open(dir.path())?;
+        {
+            let mut kv = s.write_range(everything).await;
+            assert_eq!(kv.get(b"a").unwrap().data, b"one");
+            assert!(kv.get(b"b").is_none());
+
+            // New writes land after the good prefix
+            kv.put(b"c".to_vec(), item(3, "three"))?;
+        }
+        drop(s);
+        let s = open(dir.path())?;
+        assert_eq!(s.read_range(everything).await.len(), 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{ser::SerializeMap, ser::SerializeStruct, Deserialize, Serialize, Serializer};
+
+use super::{v1, Compacted, KeyRange, Op, RangeIter};
+use crate::Item;
+
+// How many superseded revisions we keep for each key
+const MAX_HISTORY_PER_KEY: usize = 16;
+
+/// A superseded revision of a key
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Revision {
+    Put(Item),
+    Delete { version: i64 },
+}
+
+impl Revision {
+    pub fn version(&self) -> i64 {
+        match self {
+            Revision::Put(item) => item.version,
+            Revision::Delete { version } => *version,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct KeyHistory {
+    // Oldest first
+    revisions: VecDeque<Revision>,
+    // Whether we've dropped anything off the front, so we can tell
+    // "didn't exist back then" apart from "don't know anymore"
+    trimmed: bool,
+}
+
+/// The live keys plus their history, for one partition of the keyspace or all of it.
+/// The disk storage snapshots the partitions as if they were one of these.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Keyspace {
+    map: BTreeMap<Vec<u8>, Item>,
+    history: HashMap<Vec<u8>, KeyHistory>,
+    // Highest version any put or delete has used
+    revision: i64,
+    // Reads before this version are gone
+    compacted: i64,
+    // Size of the keys and values in `map`, recounted on load
+    #[serde(skip)]
+    bytes: usize,
+}
+
+impl Keyspace {
+    pub fn get(&self, key: &[u8]) -> Option<&Item> {
+        self.map.get(key)
+    }
+
+    pub fn range<'a>(&'a self, range: KeyRange<'_>) -> RangeIter<'a> {
+        Box::new(self.map.range::<[u8], _>(range))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn stored_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// `bytes` isn't serialized, so this needs calling after loading a snapshot
+    pub fn recount(&mut self) {
+        self.bytes = self
+            .map
+            .iter()
+            .map(|(key, item)| entry_size(key, item))
+            .sum();
+    }
+
+    pub fn revision(&self) -> i64 {
+        self.revision
+    }
+
+    /// The key as it was at `version`, None if it didn't exist then
+    pub fn get_at(&self, key: &[u8], version: i64) -> Result<Option<&Item>, Compacted> {
+        if version < self.compacted {
+            return Err(Compacted {
+                oldest: self.compacted,
+            });
+        }
+        if let Some(item) = self.map.get(key) {
+            if item.version <= version {
+                return Ok(Some(item));
+            }
+        }
+
+        let Some(history) = self.history.get(key) else {
+            return Ok(None);
+        };
+        match history.revisions.iter().rev().find(|r| r.version() <= version) {
+            Some(Revision::Put(item)) => Ok(Some(item)),
+            Some(Revision::Delete { .. }) => Ok(None),
+            None if history.trimmed => Err(Compacted {
+                oldest: history.revisions.front().map_or(self.compacted, |r| r.version()),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Superseded revisions of the key, oldest first
+    pub fn history(&self, key: &[u8]) -> Vec<&Revision> {
+        self.history
+            .get(key)
+            .map(|h| h.revisions.iter().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn apply(&mut self, ops: Vec<Op>) {
+        for op in ops {
+            self.apply_op(op);
+        }
+    }
+
+    pub fn apply_op(&mut self, op: Op) {
+        match op {
+            Op::Put { key, item } => {
+                self.revision = self.revision.max(item.version);
+                self.bytes += entry_size(&key, &item);
+                if let Some(old) = self.map.insert(key.clone(), item) {
+                    self.bytes -= entry_size(&key, &old);
+                    self.push_history(key, Revision::Put(old));
+                }
+            }
+            Op::Delete { key, version } => {
+                self.revision = self.revision.max(version);
+                if let Some(old) = self.map.remove(&key) {
+                    self.bytes -= entry_size(&key, &old);
+                    self.push_history(key.clone(), Revision::Put(old));
+                    self.push_history(key, Revision::Delete { version });
+                }
+            }
+            Op::Compact { version } => self.compact(version),
+            Op::Reset { revision } => {
+                *self = Keyspace {
+                    revision,
+                    compacted: revision,
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    /// The `n`th live key in order
+    pub fn key_at(&self, n: usize) -> Option<&[u8]> {
+        self.map.keys().nth(n).map(Vec::as_slice)
+    }
+
+    /// Moves every key from `at` onwards, with its history, into a new keyspace
+    pub fn split_off(&mut self, at: &[u8]) -> Keyspace {
+        let (history, moved) = std::mem::take(&mut self.history)
+            .into_iter()
+            .partition(|(key, _)| key.as_slice() < at);
+        self.history = history;
+        let mut other = Keyspace {
+            map: self.map.split_off(at),
+            history: moved,
+            revision: self.revision,
+            compacted: self.compacted,
+            bytes: 0,
+        };
+        self.recount();
+        other.recount();
+        other
+    }
+
+    fn push_history(&mut self, key: Vec<u8>, revision: Revision) {
+        let history = self.history.entry(key).or_default();
+        history.revisions.push_back(revision);
+        if history.revisions.len() > MAX_HISTORY_PER_KEY {
+            history.revisions.pop_front();
+            history.trimmed = true;
+        }
+    }
+
+    /// Drops every revision that isn't needed to read at `version` or later
+    fn compact(&mut self, version: i64) {
+        if version <= self.compacted {
+            return;
+        }
+        self.compacted = version;
+        self.history.retain(|key, history| {
+            // Keep the newest revision at or before `version`, reads at `version` still need it
+            let older = history
+                .revisions
+                .iter()
+                .take_while(|r| r.version() <= version)
+                .count();
+            if older > 1 {
+                history.revisions.drain(..older - 1);
+                history.trimmed = true;
+            }
+            // A key that's deleted as of `version` and never came back has nothing left to read
+            let dead = !self.map.contains_key(key)
+                && history.revisions.len() == 1
+                && history
+                    .revisions
+                    .back()
+                    .is_some_and(|r| matches!(r, Revision::Delete { .. }) && r.version() <= version);
+            !dead
+        });
+    }
+}
+
+impl From<v1::Keyspace> for Keyspace {
+    fn from(ks: v1::Keyspace) -> Self {
+        let mut ks = Keyspace {
+            map: ks
+                .map
+                .into_iter()
+                .map(|(key, item)| (key, item.into()))
+                .collect(),
+            history: ks
+                .history
+                .into_iter()
+                .map(|(key, history)| {
+                    let history = KeyHistory {
+                        revisions: history.revisions.into_iter().map(Into::into).collect(),
+                        trimmed: history.trimmed,
+                    };
+                    (key, history)
+                })
+                .collect(),
+            revision: ks.revision,
+            compacted: ks.compacted,
+            bytes: 0,
+        };
+        ks.recount();
+        ks
+    }
+}
+
+/// Serializes exactly like a single [`Keyspace`] holding everything in `parts`, which
+/// have to be in key order. Saves putting the partitions back together to snapshot them.
+pub struct Merged<'a> {
+    pub parts: &'a [&'a Keyspace],
+    pub revision: i64,
+}
+
+impl Serialize for Merged<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Every entry of every map, as one map
+        struct Maps<'a, M> {
+            maps: Vec<&'a M>,
+            len: usize,
+        }
+
+        impl<'a, M, K, V> Serialize for Maps<'a, M>
+        where
+            &'a M: IntoIterator<Item = (&'a K, &'a V)>,
+            K: Serialize + 'a,
+            V: Serialize + 'a,
+        {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                let mut map = serializer.serialize_map(Some(self.len))?;
+                for (key, value) in self.maps.iter().flat_map(|m| m.into_iter()) {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+
+        let map = Maps {
+            maps: self.parts.iter().map(|p| &p.map).collect(),
+            len: self.parts.iter().map(|p| p.map.len()).sum(),
+        };
+        let history = Maps {
+            maps: self.parts.iter().map(|p| &p.history).collect(),
+            len: self.parts.iter().map(|p| p.history.len()).sum(),
+        };
+        let mut ks = serializer.serialize_struct("Keyspace", 4)?;
+        ks.serialize_field("map", &map)?;
+        ks.serialize_field("history", &history)?;
+        ks.serialize_field("revision", &self.revision)?;
+        ks.serialize_field(
+            "compacted",
+            &self.parts.iter().map(|p| p.compacted).max().unwrap_or(0),
+        )?;
+        ks.end()
+    }
+}
+
+pub(crate) fn entry_size(key: &[u8], item: &Item) -> usize {
+    key.len() + item.data.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(key: &str, version: i64, data: &str) -> Op {
+        Op::Put {
+            key: key.as_bytes().to_vec(),
+            item: Item {
+                version,
+                data: data.as_bytes().to_vec(),
+                expires_at: None,
+                meta: Default::default(),
+            },
+        }
+    }
+
+    fn data_at(ks: &Keyspace, key: &str, version: i64) -> Result<Option<Vec<u8>>, Compacted> {
+        ks.get_at(key.as_bytes(), version).map(|item| item.map(|i| i.data.clone()))
+    }
+
+    #[test]
+    fn reads_and_compacts_history() {
+        let mut ks = Keyspace::default();
+        ks.apply(vec![put("a", 1, "one")]);
+        ks.apply(vec![put("a", 2, "two")]);
+        ks.apply(vec![Op::Delete {
+            key: b"a".to_vec(),
+            version: 3,
+        }]);
+        ks.apply(vec![put("a", 4, "four")]);
+        assert_eq!(ks.revision(), 4);
+
+        assert_eq!(data_at(&ks, "a", 0).unwrap(), None);
+        assert_eq!(data_at(&ks, "a", 1).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(data_at(&ks, "a", 2).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(data_at(&ks, "a", 3).unwrap(), None);
+        assert_eq!(data_at(&ks, "a", 9).unwrap(), Some(b"four".to_vec()));
+
+        ks.apply(vec![Op::Compact { version: 2 }]);
+        assert!(data_at(&ks, "a", 1).is_err());
+        assert_eq!(data_at(&ks, "a", 2).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(data_at(&ks, "a", 3).unwrap(), None);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut ks = Keyspace::default();
+        ks.apply(vec![put("a", 1, "one"), put("b", 2, "two")]);
+        ks.apply(vec![Op::Reset { revision: 10 }, put("c", 7, "seven")]);
+
+        assert_eq!(ks.revision(), 10);
+        assert_eq!(ks.len(), 1);
+        assert_eq!(ks.stored_bytes(), 6);
+        assert!(ks.get(b"a").is_none());
+        assert_eq!(data_at(&ks, "c", 10).unwrap(), Some(b"seven".to_vec()));
+        assert!(data_at(&ks, "a", 2).is_err());
+    }
+}
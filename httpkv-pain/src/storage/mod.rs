@@ -0,0 +1,105 @@
+use std::{fmt::Debug, ops::Bound};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Item;
+
+mod disk;
+mod keyspace;
+mod memory;
+mod sharded;
+mod v1;
+
+pub use disk::DiskStorage;
+pub(crate) use disk::{decode_record, encode_record};
+pub(crate) use keyspace::entry_size;
+pub use keyspace::{Keyspace, Revision};
+pub use memory::MemoryStorage;
+pub use sharded::{ReadView, Sharded, View, WriteView, MAX_PARTITION_KEYS};
+
+pub type KeyRange<'a> = (Bound<&'a [u8]>, Bound<&'a [u8]>);
+
+pub type RangeIter<'a> = Box<dyn DoubleEndedIterator<Item = (&'a Vec<u8>, &'a Item)> + Send + 'a>;
+
+/// A single mutation. A batch of these is applied atomically by [`Storage::apply`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Op {
+    Put { key: Vec<u8>, item: Item },
+    Delete { key: Vec<u8>, version: i64 },
+    /// Drop history that's only needed for reads before `version`
+    Compact { version: i64 },
+    /// Throw everything away and start over at `revision`, with no history before it.
+    /// A follower that fell too far behind gets one of these followed by a full copy.
+    Reset { revision: i64 },
+}
+
+impl Op {
+    pub fn version(&self) -> Option<i64> {
+        match self {
+            Op::Put { item, .. } => Some(item.version),
+            Op::Delete { version, .. } => Some(*version),
+            Op::Compact { .. } => None,
+            Op::Reset { revision } => Some(*revision),
+        }
+    }
+}
+
+/// How the ops are laid out in WAL records, snapshots and the change stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Format {
+    /// From before items had meta, see [`v1`]
+    V1,
+    V2,
+}
+
+/// The requested version is older than anything we still have history for
+#[derive(Debug)]
+pub struct Compacted {
+    pub oldest: i64,
+}
+
+/// Where the keyspace is kept across restarts. The keys themselves live in memory, in
+/// the partitions of a [`Sharded`], which hands each batch to the storage before it
+/// applies it. Calls are serialized by the `Sharded`, so implementations don't need
+/// to do their own locking.
+pub trait Storage: Send + Sync + Debug {
+    /// Everything there was when the storage was opened. Only called once, on startup.
+    fn load(&mut self) -> Keyspace;
+
+    /// Must only return once the batch is durable
+    fn append(&mut self, ops: &[Op]) -> anyhow::Result<()>;
+
+    /// Compacts whatever the implementation has accumulated since the last call
+    /// (e.g. folding a WAL into a snapshot). Called periodically in the background
+    /// with every partition, in key order, and no writes in flight.
+    fn checkpoint(&mut self, _parts: &[&Keyspace], _revision: i64) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// The smallest key that sorts after every key starting with `prefix`, or None
+/// if there isn't one (empty prefix, or nothing but 0xFF)
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prefix_end;
+
+    #[test]
+    fn prefix_end_bounds_the_prefix() {
+        assert_eq!(prefix_end(b"ab").as_deref(), Some(&b"ac"[..]));
+        assert_eq!(prefix_end(b"a\xff").as_deref(), Some(&b"b"[..]));
+        assert_eq!(prefix_end(b"a\xfe\xff").as_deref(), Some(&b"a\xff"[..]));
+        assert_eq!(prefix_end(b""), None);
+        assert_eq!(prefix_end(b"\xff\xff"), None);
+    }
+}
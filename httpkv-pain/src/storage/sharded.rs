@@ -0,0 +1,630 @@
+use std::{
+    collections::BTreeSet,
+    ops::{Bound, Deref},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, Mutex as SyncMutex, RwLock as SyncRwLock,
+    },
+    time::Instant,
+};
+
+use anyhow::anyhow;
+use tokio::sync::{Mutex, MutexGuard, OwnedRwLockReadGuard, OwnedRwLockWriteGuard, RwLock};
+use tracing::{debug, info};
+
+use super::{Compacted, KeyRange, Keyspace, Op, RangeIter, Revision, Storage};
+use crate::{
+    index::Indexes,
+    metrics,
+    quota::Quotas,
+    watch::{Batch, Watcher},
+    Item,
+};
+
+/// A partition is split in two once it holds more keys than this
+pub const MAX_PARTITION_KEYS: usize = 4096;
+
+/// The keyspace split into contiguous key ranges, each behind its own lock, so a write
+/// only holds up the readers and writers of the partitions it touches. Partitions split
+/// in half as they grow.
+///
+/// Views lock the partitions they need in key order, so two of them can't deadlock,
+/// and a view over several partitions sees them all at the same point. Writers then
+/// take the log lock, which hands out versions and gets each batch into the storage
+/// and out to the watchers in order.
+#[derive(Debug)]
+pub struct Sharded {
+    // In key order. The first starts at the empty key, each ends where the next starts.
+    partitions: SyncRwLock<Vec<Arc<Partition>>>,
+    log: Mutex<Box<dyn Storage>>,
+    // Highest version applied, so far as anyone holding partition locks can tell
+    revision: AtomicI64,
+    max_partition_keys: usize,
+    watcher: Option<Arc<Watcher>>,
+    indexes: Option<Arc<Indexes>>,
+    quotas: Option<Arc<Quotas>>,
+    // Every key with an expiry, soonest first, so the reaper needn't look at the rest
+    expiring: SyncMutex<BTreeSet<(i64, Vec<u8>)>>,
+}
+
+#[derive(Debug)]
+struct Partition {
+    start: Vec<u8>,
+    // None for the last one
+    end: Option<Vec<u8>>,
+    ks: Arc<RwLock<Keyspace>>,
+    // Set when it's split. Its range never changes otherwise, so whoever locks it and
+    // finds this unset knows it still covers what they were after.
+    retired: AtomicBool,
+}
+
+impl Partition {
+    fn new(start: Vec<u8>, end: Option<Vec<u8>>, ks: Keyspace) -> Self {
+        Self {
+            start,
+            end,
+            ks: Arc::new(RwLock::new(ks)),
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        key >= self.start.as_slice() && self.end.as_deref().is_none_or(|end| key < end)
+    }
+
+    fn overlaps(&self, (lower, upper): KeyRange<'_>) -> bool {
+        let starts_before_upper = match upper {
+            Bound::Included(upper) => self.start.as_slice() <= upper,
+            Bound::Excluded(upper) => self.start.as_slice() < upper,
+            Bound::Unbounded => true,
+        };
+        let ends_after_lower = match (lower, &self.end) {
+            (Bound::Included(lower) | Bound::Excluded(lower), Some(end)) => lower < end.as_slice(),
+            _ => true,
+        };
+        starts_before_upper && ends_after_lower
+    }
+}
+
+impl Sharded {
+    /// `max_partition_keys` of `usize::MAX` keeps everything in one partition
+    pub fn new(mut storage: Box<dyn Storage>, max_partition_keys: usize) -> Self {
+        let mut rest = storage.load();
+        let revision = rest.revision();
+        // Carve off full partitions from the end, so each split only moves what it keeps
+        let mut partitions = Vec::new();
+        let mut end = None;
+        while rest.len() > max_partition_keys {
+            let at = rest
+                .key_at(rest.len() - max_partition_keys / 2)
+                .unwrap()
+                .to_vec();
+            let tail = rest.split_off(&at);
+            partitions.push(Arc::new(Partition::new(at.clone(), end, tail)));
+            end = Some(at);
+        }
+        partitions.push(Arc::new(Partition::new(Vec::new(), end, rest)));
+        partitions.reverse();
+        info!(partitions = partitions.len(), "Partitioned keyspace");
+
+        let mut sharded = Self {
+            partitions: SyncRwLock::new(partitions),
+            log: Mutex::new(storage),
+            revision: AtomicI64::new(revision),
+            max_partition_keys,
+            watcher: None,
+            indexes: None,
+            quotas: None,
+            expiring: SyncMutex::default(),
+        };
+        let mut expiring = BTreeSet::new();
+        sharded.for_each_loaded(|key, item| {
+            if let Some(at) = item.expires_at {
+                expiring.insert((at, key.to_vec()));
+            }
+        });
+        *sharded.expiring.get_mut().unwrap() = expiring;
+        sharded
+    }
+
+    /// Publish every batch to `watcher` as it's applied
+    pub(crate) fn watched(mut self, watcher: Arc<Watcher>) -> Self {
+        self.watcher = Some(watcher);
+        self
+    }
+
+    /// Keep `indexes` up to date with every batch, starting with what's loaded now
+    pub(crate) fn indexed(mut self, indexes: Arc<Indexes>) -> Self {
+        self.for_each_loaded(|key, item| indexes.update(key, None, Some(item)));
+        self.indexes = Some(indexes);
+        self
+    }
+
+    pub(crate) fn indexes(&self) -> Option<&Arc<Indexes>> {
+        self.indexes.as_ref()
+    }
+
+    /// Count usage against `quotas` with every batch, starting with what's loaded now
+    pub(crate) fn limited(mut self, quotas: Arc<Quotas>) -> Self {
+        self.for_each_loaded(|key, item| quotas.update(key, None, Some(item)));
+        self.quotas = Some(quotas);
+        self
+    }
+
+    pub(crate) fn quotas(&self) -> Option<&Arc<Quotas>> {
+        self.quotas.as_ref()
+    }
+
+    fn for_each_loaded(&mut self, mut f: impl FnMut(&[u8], &Item)) {
+        let everything = (Bound::Unbounded, Bound::Unbounded);
+        for part in self.partitions.get_mut().unwrap().iter() {
+            let ks = part.ks.try_read().expect("nobody else has the store yet");
+            for (key, item) in ks.range(everything) {
+                f(key, item);
+            }
+        }
+    }
+
+    /// Up to `limit` keys that had expired as of `now`, soonest first. Takes no partition
+    /// locks, so they can be rewritten before the caller gets to them.
+    pub fn expired(&self, now: i64, limit: usize) -> Vec<Vec<u8>> {
+        self.expiring
+            .lock()
+            .unwrap()
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    /// The highest version written so far, without waiting on any locks
+    pub fn revision(&self) -> i64 {
+        self.revision.load(Ordering::Acquire)
+    }
+
+    pub fn partition_count(&self) -> usize {
+        self.partitions.read().unwrap().len()
+    }
+
+    /// A view of the partitions holding `keys`
+    pub async fn read<K: AsRef<[u8]>>(&self, keys: &[K]) -> ReadView {
+        self.read_where(|p| keys.iter().any(|key| p.contains(key.as_ref())))
+            .await
+    }
+
+    pub async fn read_range(&self, range: KeyRange<'_>) -> ReadView {
+        self.read_where(|p| p.overlaps(range)).await
+    }
+
+    /// A view of the partitions holding `keys` that can write to them
+    pub async fn write<K: AsRef<[u8]>>(&self, keys: &[K]) -> WriteView<'_> {
+        self.write_where(|p| keys.iter().any(|key| p.contains(key.as_ref())))
+            .await
+    }
+
+    pub async fn write_range(&self, range: KeyRange<'_>) -> WriteView<'_> {
+        self.write_where(|p| p.overlaps(range)).await
+    }
+
+    /// Hands the whole keyspace to the storage to compact, see [`Storage::checkpoint`]
+    pub async fn checkpoint(&self) -> anyhow::Result<()> {
+        let view = self.read_range((Bound::Unbounded, Bound::Unbounded)).await;
+        let mut log = self.log.lock().await;
+        let parts: Vec<&Keyspace> = view.parts.iter().map(|part| &*part.guard).collect();
+        log.checkpoint(&parts, view.revision)
+    }
+
+    async fn read_where(&self, wanted: impl Fn(&Partition) -> bool) -> ReadView {
+        let started = Instant::now();
+        let parts = self.lock_where(wanted, RwLock::read_owned).await;
+        metrics::observe_lock_wait("read", started);
+        View {
+            parts,
+            revision: self.revision(),
+        }
+    }
+
+    async fn write_where(&self, wanted: impl Fn(&Partition) -> bool) -> WriteView<'_> {
+        let started = Instant::now();
+        let parts = self.lock_where(wanted, RwLock::write_owned).await;
+        let log = self.log.lock().await;
+        metrics::observe_lock_wait("write", started);
+        WriteView {
+            view: View {
+                parts,
+                revision: self.revision(),
+            },
+            log,
+            sharded: self,
+        }
+    }
+
+    /// Locks every partition `wanted` picks, in key order, starting over if one of
+    /// them was split while we waited on it
+    async fn lock_where<G, F>(
+        &self,
+        wanted: impl Fn(&Partition) -> bool,
+        lock: impl Fn(Arc<RwLock<Keyspace>>) -> F,
+    ) -> Vec<Part<G>>
+    where
+        F: std::future::Future<Output = G>,
+    {
+        'retry: loop {
+            let picked: Vec<Arc<Partition>> = self
+                .partitions
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|p| wanted(p))
+                .cloned()
+                .collect();
+            let mut parts = Vec::with_capacity(picked.len());
+            for partition in picked {
+                let guard = lock(partition.ks.clone()).await;
+                if partition.retired.load(Ordering::Acquire) {
+                    continue 'retry;
+                }
+                parts.push(Part { partition, guard });
+            }
+            return parts;
+        }
+    }
+}
+
+struct Part<G> {
+    partition: Arc<Partition>,
+    guard: G,
+}
+
+/// Some of the partitions, locked. Reads of keys outside them panic, so lock
+/// everything you're going to look at up front.
+pub struct View<G> {
+    // In key order
+    parts: Vec<Part<G>>,
+    revision: i64,
+}
+
+pub type ReadView = View<OwnedRwLockReadGuard<Keyspace>>;
+
+impl<G: Deref<Target = Keyspace>> View<G> {
+    fn find(&self, key: &[u8]) -> Option<usize> {
+        let i = self
+            .parts
+            .partition_point(|part| part.partition.start.as_slice() <= key);
+        let i = i.checked_sub(1)?;
+        self.parts[i].partition.contains(key).then_some(i)
+    }
+
+    fn part(&self, key: &[u8]) -> &Keyspace {
+        let i = self
+            .find(key)
+            .expect("read a key outside the locked partitions");
+        &self.parts[i].guard
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Item> {
+        self.part(key).get(key)
+    }
+
+    /// Like `get`, but hides items whose TTL has passed and are waiting to be reaped
+    pub fn get_live(&self, key: &[u8]) -> Option<&Item> {
+        self.get(key).filter(|item| !item.is_expired())
+    }
+
+    /// The key as of `version`, None if it didn't exist then
+    pub fn get_at(&self, key: &[u8], version: i64) -> Result<Option<&Item>, Compacted> {
+        self.part(key).get_at(key, version)
+    }
+
+    /// Superseded revisions of the key, oldest first
+    pub fn history(&self, key: &[u8]) -> Vec<&Revision> {
+        self.part(key).history(key)
+    }
+
+    /// The highest version written, as of when the view was taken
+    pub fn revision(&self) -> i64 {
+        self.revision
+    }
+
+    /// In key order across the partitions in the view
+    pub fn range<'a>(&'a self, range: KeyRange<'_>) -> RangeIter<'a> {
+        let iters: Vec<RangeIter<'a>> = self
+            .parts
+            .iter()
+            .map(|part| part.guard.range(range))
+            .collect();
+        Box::new(iters.into_iter().flatten())
+    }
+
+    /// Keys in the partitions in the view
+    pub fn len(&self) -> usize {
+        self.parts.iter().map(|part| part.guard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Total bytes of live keys and values in the partitions in the view
+    pub fn stored_bytes(&self) -> usize {
+        self.parts
+            .iter()
+            .map(|part| part.guard.stored_bytes())
+            .sum()
+    }
+
+    /// Whether the view holds every partition there is
+    fn covers_everything(&self) -> bool {
+        let mut next: Option<&[u8]> = Some(&[]);
+        for part in &self.parts {
+            if next != Some(part.partition.start.as_slice()) {
+                return false;
+            }
+            next = part.partition.end.as_deref();
+        }
+        next.is_none()
+    }
+}
+
+/// A view that can also write. Holds the log lock, so there's one of these at a time.
+pub struct WriteView<'a> {
+    view: View<OwnedRwLockWriteGuard<Keyspace>>,
+    log: MutexGuard<'a, Box<dyn Storage>>,
+    sharded: &'a Sharded,
+}
+
+impl Deref for WriteView<'_> {
+    type Target = View<OwnedRwLockWriteGuard<Keyspace>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.view
+    }
+}
+
+impl WriteView<'_> {
+    /// Nobody else can write until this view is dropped, so this is safe to use for
+    /// the next batch
+    pub fn next_version(&self) -> i64 {
+        self.view.revision + 1
+    }
+
+    /// Applies every op or none of them, returning once the batch is durable. Puts and
+    /// deletes have to be in the view, compactions and resets need all of it.
+    pub fn apply(&mut self, ops: Vec<Op>) -> anyhow::Result<()> {
+        for op in &ops {
+            let covered = match op {
+                Op::Put { key, .. } | Op::Delete { key, .. } => self.view.find(key).is_some(),
+                Op::Compact { .. } | Op::Reset { .. } => self.view.covers_everything(),
+            };
+            if !covered {
+                return Err(anyhow!("batch writes outside the locked partitions"));
+            }
+        }
+
+        let published: Vec<Op> = ops
+            .iter()
+            .filter(|op| match op {
+                // Deleting something that isn't there isn't a change
+                Op::Delete { key, .. } => self.view.get(key).is_some(),
+                // Followers compact on their own schedule
+                Op::Compact { .. } => false,
+                Op::Put { .. } | Op::Reset { .. } => true,
+            })
+            .cloned()
+            .collect();
+        self.log.append(&ops)?;
+
+        let mut revision = self.view.revision;
+        let mut expiring = self.sharded.expiring.lock().unwrap();
+        for op in ops {
+            match &op {
+                Op::Reset { revision: reset } => revision = *reset,
+                op => revision = revision.max(op.version().unwrap_or(revision)),
+            }
+            match &op {
+                Op::Put { key, .. } | Op::Delete { key, .. } => {
+                    let i = self.view.find(key).unwrap();
+                    let old = self.view.parts[i].guard.get(key);
+                    let new = match &op {
+                        Op::Put { item, .. } => Some(item),
+                        _ => None,
+                    };
+                    if let Some(indexes) = &self.sharded.indexes {
+                        indexes.update(key, old, new);
+                    }
+                    if let Some(quotas) = &self.sharded.quotas {
+                        quotas.update(key, old, new);
+                    }
+                    if let Some(at) = old.and_then(|item| item.expires_at) {
+                        expiring.remove(&(at, key.clone()));
+                    }
+                    if let Some(at) = new.and_then(|item| item.expires_at) {
+                        expiring.insert((at, key.clone()));
+                    }
+                    self.view.parts[i].guard.apply_op(op);
+                }
+                Op::Compact { .. } | Op::Reset { .. } => {
+                    if let Op::Reset { .. } = op {
+                        if let Some(indexes) = &self.sharded.indexes {
+                            indexes.clear();
+                        }
+                        if let Some(quotas) = &self.sharded.quotas {
+                            quotas.clear();
+                        }
+                        expiring.clear();
+                    }
+                    for part in &mut self.view.parts {
+                        part.guard.apply_op(op.clone());
+                    }
+                }
+            }
+        }
+        drop(expiring);
+        self.view.revision = revision;
+        self.sharded.revision.store(revision, Ordering::Release);
+
+        if let (Some(watcher), Some(version)) = (
+            &self.sharded.watcher,
+            published.iter().filter_map(Op::version).max(),
+        ) {
+            debug!(count = published.len(), "publishing batch");
+            watcher.publish(Batch {
+                version,
+                ops: published,
+            });
+        }
+
+        // Back to front so splitting doesn't shift what's still to check. A big batch
+        // can need more than one split, so both halves get checked again.
+        let mut i = self.view.parts.len();
+        while i > 0 {
+            i -= 1;
+            if self.view.parts[i].guard.len() > self.sharded.max_partition_keys {
+                self.split(i);
+                i += 2;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, item: Item) -> anyhow::Result<()> {
+        self.apply(vec![Op::Put { key, item }])
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) -> anyhow::Result<()> {
+        let version = self.next_version();
+        self.apply(vec![Op::Delete { key, version }])
+    }
+
+    pub fn compact(&mut self, version: i64) -> anyhow::Result<()> {
+        self.apply(vec![Op::Compact { version }])
+    }
+
+    /// Replaces the `i`th partition with two halves, both still locked by this view
+    fn split(&mut self, i: usize) {
+        let old = &mut self.view.parts[i];
+        let mut left = std::mem::take(&mut *old.guard);
+        let at = left.key_at(left.len() / 2).unwrap().to_vec();
+        let right = left.split_off(&at);
+        let halves = [
+            Partition::new(old.partition.start.clone(), Some(at.clone()), left),
+            Partition::new(at, old.partition.end.clone(), right),
+        ]
+        .map(|partition| {
+            let partition = Arc::new(partition);
+            // Nobody else can see it yet
+            let guard = partition.ks.clone().try_write_owned().unwrap();
+            Part { partition, guard }
+        });
+
+        old.partition.retired.store(true, Ordering::Release);
+        {
+            let mut partitions = self.sharded.partitions.write().unwrap();
+            let n = partitions
+                .iter()
+                .position(|p| Arc::ptr_eq(p, &old.partition))
+                .unwrap();
+            partitions.splice(n..=n, halves.iter().map(|half| half.partition.clone()));
+        }
+        debug!(
+            at = %String::from_utf8_lossy(&halves[1].partition.start),
+            "split partition"
+        );
+        self.view.parts.splice(i..=i, halves);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn put(key: &[u8], version: i64) -> Op {
+        Op::Put {
+            key: key.to_vec(),
+            item: Item {
+                version,
+                data: key.to_vec(),
+                expires_at: None,
+                meta: Default::default(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn splits_and_lists_across_partitions() -> anyhow::Result<()> {
+        let sharded = Sharded::new(Box::new(MemoryStorage::new()), 4);
+        for n in (0..20u8).rev() {
+            let key = [b'k', n];
+            let mut kv = sharded.write(&[key]).await;
+            let version = kv.next_version();
+            kv.apply(vec![put(&key, version)])?;
+        }
+        assert!(sharded.partition_count() >= 5);
+        assert_eq!(sharded.revision(), 20);
+
+        let kv = sharded.read(&[[b'k', 7]]).await;
+        assert_eq!(kv.get(&[b'k', 7]).unwrap().version, 13);
+        drop(kv);
+
+        let everything = (Bound::Unbounded, Bound::Unbounded);
+        let kv = sharded.read_range(everything).await;
+        let keys: Vec<u8> = kv.range(everything).map(|(key, _)| key[1]).collect();
+        assert_eq!(keys, (0..20).collect::<Vec<_>>());
+        let keys: Vec<u8> = kv.range(everything).rev().map(|(key, _)| key[1]).collect();
+        assert_eq!(keys, (0..20).rev().collect::<Vec<_>>());
+        drop(kv);
+
+        // A range only locks what it overlaps, and the view can't write outside it
+        let range = (
+            Bound::Included(&b"k\x05"[..]),
+            Bound::Excluded(&b"k\x09"[..]),
+        );
+        let mut kv = sharded.write_range(range).await;
+        assert!(kv.parts.len() < sharded.partition_count());
+        let keys: Vec<u8> = kv.range(range).map(|(key, _)| key[1]).collect();
+        assert_eq!(keys, [5, 6, 7, 8]);
+        assert!(kv.apply(vec![Op::Compact { version: 1 }]).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tracks_what_expires() -> anyhow::Result<()> {
+        let sharded = Sharded::new(Box::new(MemoryStorage::new()), 2);
+        let expiring = |key: &[u8], version, at| {
+            let mut op = put(key, version);
+            if let Op::Put { item, .. } = &mut op {
+                item.expires_at = Some(at);
+            }
+            op
+        };
+        let keys = [b"a", b"b", b"c", b"d"];
+        let mut kv = sharded
+            .write_range((Bound::Unbounded, Bound::Unbounded))
+            .await;
+        kv.apply(vec![
+            expiring(b"a", 1, 30),
+            expiring(b"b", 1, 10),
+            expiring(b"c", 1, 20),
+            put(b"d", 1),
+        ])?;
+        drop(kv);
+        assert_eq!(sharded.expired(20, 10), [b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(sharded.expired(100, 1), [b"b".to_vec()]);
+
+        // Rewriting or deleting a key takes its old expiry with it
+        let mut kv = sharded.write(&keys).await;
+        kv.apply(vec![
+            put(b"b", 2),
+            Op::Delete {
+                key: b"c".to_vec(),
+                version: 2,
+            },
+            expiring(b"d", 2, 5),
+        ])?;
+        drop(kv);
+        assert_eq!(sharded.expired(100, 10), [b"d".to_vec(), b"a".to_vec()]);
+        Ok(())
+    }
+}
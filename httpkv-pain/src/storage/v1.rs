@@ -0,0 +1,106 @@
+//! The bincode layout from before items carried [`Meta`](crate::Meta), so data dirs
+//! and leaders from then still load. Everything decodes into the current types, with
+//! empty meta, and only followers from then get anything encoded this way.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Item {
+    version: i64,
+    data: Vec<u8>,
+    expires_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum Op {
+    Put { key: Vec<u8>, item: Item },
+    Delete { key: Vec<u8>, version: i64 },
+    Compact { version: i64 },
+    Reset { revision: i64 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) enum Revision {
+    Put(Item),
+    Delete { version: i64 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct KeyHistory {
+    pub(super) revisions: VecDeque<Revision>,
+    pub(super) trimmed: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Keyspace {
+    pub(super) map: BTreeMap<Vec<u8>, Item>,
+    pub(super) history: HashMap<Vec<u8>, KeyHistory>,
+    pub(super) revision: i64,
+    pub(super) compacted: i64,
+}
+
+impl From<Item> for crate::Item {
+    fn from(item: Item) -> Self {
+        Self {
+            version: item.version,
+            data: item.data,
+            expires_at: item.expires_at,
+            meta: Default::default(),
+        }
+    }
+}
+
+/// Drops the meta, there was nowhere to put it
+impl From<&crate::Item> for Item {
+    fn from(item: &crate::Item) -> Self {
+        Self {
+            version: item.version,
+            data: item.data.clone(),
+            expires_at: item.expires_at,
+        }
+    }
+}
+
+impl From<Op> for super::Op {
+    fn from(op: Op) -> Self {
+        match op {
+            Op::Put { key, item } => Self::Put {
+                key,
+                item: item.into(),
+            },
+            Op::Delete { key, version } => Self::Delete { key, version },
+            Op::Compact { version } => Self::Compact { version },
+            Op::Reset { revision } => Self::Reset { revision },
+        }
+    }
+}
+
+impl From<&super::Op> for Op {
+    fn from(op: &super::Op) -> Self {
+        match op {
+            super::Op::Put { key, item } => Self::Put {
+                key: key.clone(),
+                item: item.into(),
+            },
+            super::Op::Delete { key, version } => Self::Delete {
+                key: key.clone(),
+                version: *version,
+            },
+            super::Op::Compact { version } => Self::Compact { version: *version },
+            super::Op::Reset { revision } => Self::Reset {
+                revision: *revision,
+            },
+        }
+    }
+}
+
+impl From<Revision> for super::Revision {
+    fn from(revision: Revision) -> Self {
+        match revision {
+            Revision::Put(item) => Self::Put(item.into()),
+            Revision::Delete { version } => Self::Delete { version },
+        }
+    }
+}
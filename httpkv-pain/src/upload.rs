@@ -0,0 +1,248 @@
+use std::{
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use anyhow::anyhow;
+use axum::{body::Bytes, http::StatusCode};
+use tracing::debug;
+
+use crate::AppError;
+
+// An upload that hasn't seen a chunk in this long is thrown away
+pub const SESSION_TTL_MILLIS: i64 = 60 * 60 * 1000;
+// Per chunk, and for the assembled value
+pub const MAX_CHUNK_BYTES: usize = 1 << 20;
+pub const MAX_UPLOAD_BYTES: usize = 64 << 20;
+// Chunk numbers run from 0 to below this, enough for a full upload of full chunks
+pub const MAX_CHUNKS: u32 = (MAX_UPLOAD_BYTES / MAX_CHUNK_BYTES) as u32;
+// Sessions in progress at once, across everybody
+pub const MAX_SESSIONS: usize = 256;
+// Chunks held in memory at once, across every session
+pub const MAX_BUFFERED_BYTES: usize = 1 << 30;
+
+/// A value being uploaded a chunk at a time. Chunks can arrive in any order and be
+/// resent, they're put together in order on commit.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub key: Vec<u8>,
+    pub chunks: BTreeMap<u32, Bytes>,
+    pub bytes: usize,
+    pub expires_at: i64,
+}
+
+impl Session {
+    /// The chunk numbers missing from 0 up to the highest one we have
+    pub fn missing(&self) -> Vec<u32> {
+        let last = self.chunks.keys().next_back().map_or(0, |n| n + 1);
+        (0..last.min(MAX_CHUNKS))
+            .filter(|n| !self.chunks.contains_key(n))
+            .collect()
+    }
+
+    pub fn assemble(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.bytes);
+        for chunk in self.chunks.values() {
+            data.extend_from_slice(chunk);
+        }
+        data
+    }
+}
+
+/// Upload sessions in progress. They only live in memory, a restart loses them
+/// and the client has to start over.
+#[derive(Debug, Default)]
+pub struct Uploads {
+    sessions: Mutex<Sessions>,
+}
+
+#[derive(Debug, Default)]
+struct Sessions {
+    by_id: HashMap<String, Session>,
+    // Chunk bytes across all of them
+    bytes: usize,
+}
+
+impl Uploads {
+    /// Returns the new session's id, or None when there are already MAX_SESSIONS
+    pub fn start(&self, key: Vec<u8>) -> Option<(String, Session)> {
+        let session = Session {
+            key,
+            chunks: BTreeMap::new(),
+            bytes: 0,
+            expires_at: crate::now_millis() + SESSION_TTL_MILLIS,
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.by_id.len() >= MAX_SESSIONS {
+            return None;
+        }
+        let id = new_id();
+        sessions.by_id.insert(id.clone(), session.clone());
+        Some((id, session))
+    }
+
+    pub fn get(&self, id: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().by_id.get(id).cloned()
+    }
+
+    /// Stores chunk `n` of the session if it exists, replacing whatever was sent as `n`
+    /// before and pushing its expiry out. Returns the session's bytes so far.
+    pub fn put_chunk(&self, id: &str, n: u32, chunk: Bytes) -> Option<Result<usize, AppError>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let Sessions {
+            by_id,
+            bytes: buffered,
+        } = &mut *sessions;
+        let session = by_id.get_mut(id)?;
+        session.expires_at = crate::now_millis() + SESSION_TTL_MILLIS;
+
+        let replaced = session.chunks.get(&n).map_or(0, Bytes::len);
+        let bytes = session.bytes - replaced + chunk.len();
+        if bytes > MAX_UPLOAD_BYTES {
+            return Some(Err(AppError::CustomCode(
+                anyhow!("Uploads can be at most {} bytes", MAX_UPLOAD_BYTES),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            )));
+        }
+        let total = *buffered - replaced + chunk.len();
+        if total > MAX_BUFFERED_BYTES {
+            return Some(Err(AppError::CustomCode(
+                anyhow!("Too much is being uploaded at once, try again later"),
+                StatusCode::INSUFFICIENT_STORAGE,
+            )));
+        }
+        session.chunks.insert(n, chunk);
+        session.bytes = bytes;
+        *buffered = total;
+        Some(Ok(bytes))
+    }
+
+    pub fn remove(&self, id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.by_id.remove(id)?;
+        sessions.bytes -= session.bytes;
+        Some(session)
+    }
+
+    /// Puts back a session that failed to commit, unless it was restarted in between
+    pub fn restore(&self, id: String, session: Session) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Entry::Vacant(entry) = sessions.by_id.entry(id) {
+            let bytes = session.bytes;
+            entry.insert(session);
+            sessions.bytes += bytes;
+        }
+    }
+
+    /// Drops every session that's gone quiet
+    pub fn reap(&self) {
+        let now = crate::now_millis();
+        let mut sessions = self.sessions.lock().unwrap();
+        let Sessions { by_id, bytes } = &mut *sessions;
+        let before = by_id.len();
+        by_id.retain(|_, session| {
+            let keep = session.expires_at > now;
+            if !keep {
+                *bytes -= session.bytes;
+            }
+            keep
+        });
+        if by_id.len() < before {
+            debug!(count = before - by_id.len(), "reaped upload sessions");
+        }
+    }
+}
+
+/// 128 random bits from the OS as hex, so nobody can guess someone else's
+fn new_id() -> String {
+    let mut bytes = [0; 16];
+    getrandom::fill(&mut bytes).expect("the OS has no randomness to give");
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(result: Option<Result<usize, AppError>>) -> Result<usize, StatusCode> {
+        result.unwrap().map_err(|e| match e {
+            AppError::CustomCode(_, status) => status,
+            AppError::Anyhow(e) => panic!("{:?}", e),
+        })
+    }
+
+    #[test]
+    fn assembles_chunks_in_order() {
+        let uploads = Uploads::default();
+        let (id, _) = uploads.start(b"a".to_vec()).unwrap();
+        for (n, chunk) in [(2, "c"), (0, "a"), (0, "A")] {
+            uploads.put_chunk(&id, n, Bytes::from_static(chunk.as_bytes()));
+        }
+        let session = uploads.get(&id).unwrap();
+        assert_eq!(session.missing(), vec![1]);
+        assert_eq!(session.bytes, 2);
+
+        uploads.put_chunk(&id, 1, Bytes::from_static(b"b"));
+        let session = uploads.get(&id).unwrap();
+        assert!(session.missing().is_empty());
+        assert_eq!(session.assemble(), b"Abc");
+        assert_eq!(uploads.sessions.lock().unwrap().bytes, 3);
+
+        uploads.reap();
+        assert!(uploads.get(&id).is_some());
+        uploads
+            .sessions
+            .lock()
+            .unwrap()
+            .by_id
+            .get_mut(&id)
+            .unwrap()
+            .expires_at = 0;
+        uploads.reap();
+        assert!(uploads.get(&id).is_none());
+        assert_eq!(uploads.sessions.lock().unwrap().bytes, 0);
+    }
+
+    #[test]
+    fn caps_what_is_buffered() {
+        let uploads = Uploads::default();
+        // Clones share the one buffer, but each counts in full
+        let chunk = Bytes::from(vec![0; MAX_CHUNK_BYTES]);
+        let sessions = MAX_BUFFERED_BYTES / MAX_UPLOAD_BYTES;
+        let ids: Vec<String> = (0..sessions)
+            .map(|_| uploads.start(b"a".to_vec()).unwrap().0)
+            .collect();
+        for id in &ids {
+            for n in 0..MAX_CHUNKS {
+                assert!(status(uploads.put_chunk(id, n, chunk.clone())).is_ok());
+            }
+            let over = uploads.put_chunk(id, 0, Bytes::from(vec![0; MAX_CHUNK_BYTES + 1]));
+            assert_eq!(status(over), Err(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        let (id, _) = uploads.start(b"a".to_vec()).unwrap();
+        let over = uploads.put_chunk(&id, 0, Bytes::from_static(b"x"));
+        assert_eq!(status(over), Err(StatusCode::INSUFFICIENT_STORAGE));
+
+        // Replacing a chunk only counts the difference
+        assert!(status(uploads.put_chunk(&ids[0], 0, Bytes::new())).is_ok());
+        assert!(status(uploads.put_chunk(&id, 0, Bytes::from_static(b"x"))).is_ok());
+        uploads.remove(&ids[1]);
+        assert_eq!(
+            uploads.sessions.lock().unwrap().bytes,
+            MAX_BUFFERED_BYTES - MAX_UPLOAD_BYTES - MAX_CHUNK_BYTES + 1
+        );
+    }
+
+    #[test]
+    fn caps_sessions() {
+        let uploads = Uploads::default();
+        let ids: Vec<String> = (0..MAX_SESSIONS)
+            .map(|_| uploads.start(b"a".to_vec()).unwrap().0)
+            .collect();
+        assert_eq!(ids[0].len(), 32);
+        assert_ne!(ids[0], ids[1]);
+        assert!(uploads.start(b"a".to_vec()).is_none());
+        uploads.remove(&ids[0]);
+        assert!(uploads.start(b"a".to_vec()).is_some());
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::storage::{entry_size, Compacted, Op};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+// How many recent batches we keep around for watchers and followers resuming from a version
+const HISTORY_LEN: usize = 10_000;
+// And how much of their keys and values, whichever runs out first
+const HISTORY_BYTES: usize = 256 << 20;
+// How far a live watcher can fall behind before we cut it off
+const CHANNEL_LEN: usize = 1024;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Put,
+    Delete,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event<'a> {
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    // Might not be UTF-8, so it's up to the route how to encode it
+    #[serde(skip)]
+    pub key: &'a [u8],
+    pub version: i64,
+    #[serde(skip)]
+    pub data: Option<&'a [u8]>,
+}
+
+/// One applied batch, minus anything that didn't change a key
+#[derive(Debug)]
+pub struct Batch {
+    /// The highest version in `ops`
+    pub version: i64,
+    pub ops: Vec<Op>,
+}
+
+impl Batch {
+    /// The puts and deletes in the batch, in order
+    pub fn events(&self) -> impl Iterator<Item = Event<'_>> {
+        self.ops.iter().filter_map(|op| match op {
+            Op::Put { key, item } => Some(Event {
+                kind: EventKind::Put,
+                key,
+                version: item.version,
+                data: Some(&item.data),
+            }),
+            Op::Delete { key, version } => Some(Event {
+                kind: EventKind::Delete,
+                key,
+                version: *version,
+                data: None,
+            }),
+            Op::Compact { .. } | Op::Reset { .. } => None,
+        })
+    }
+
+    /// Roughly what it's holding on to, its keys and values
+    fn bytes(&self) -> usize {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                Op::Put { key, item } => entry_size(key, item),
+                Op::Delete { key, .. } => key.len(),
+                Op::Compact { .. } | Op::Reset { .. } => 0,
+            })
+            .sum()
+    }
+}
+
+pub struct Subscription {
+    /// Batches from the history, after the requested version
+    pub replay: Vec<Arc<Batch>>,
+    /// Everything after `replay`
+    pub rx: broadcast::Receiver<Arc<Batch>>,
+}
+
+/// Fans out every committed batch to the watchers and followers, and keeps a bounded
+/// history so one that disconnects can pick up where it left off. The history drops
+/// its oldest batches past HISTORY_LEN of them or HISTORY_BYTES between them.
+#[derive(Debug)]
+pub struct Watcher {
+    tx: broadcast::Sender<Arc<Batch>>,
+    history: Mutex<History>,
+    // HISTORY_BYTES, but tests get by with less
+    history_bytes: usize,
+}
+
+#[derive(Debug)]
+struct History {
+    batches: VecDeque<Arc<Batch>>,
+    // `Batch::bytes` of everything in `batches`
+    bytes: usize,
+    // Version of the last batch we dropped off the front
+    trimmed_through: Option<i64>,
+}
+
+impl Watcher {
+    /// `revision` is where the storage is at when we start, we have no history before that
+    pub fn new(revision: i64) -> Self {
+        Self {
+            tx: broadcast::channel(CHANNEL_LEN).0,
+            history: Mutex::new(History {
+                batches: VecDeque::new(),
+                bytes: 0,
+                trimmed_through: Some(revision),
+            }),
+            history_bytes: HISTORY_BYTES,
+        }
+    }
+
+    pub(crate) fn publish(&self, batch: Batch) {
+        // Send while holding the history lock so `subscribe` sees each batch
+        // either in the history or on the channel, never both or neither
+        let mut history = self.history.lock().unwrap();
+        if let Some(Op::Reset { revision }) = batch.ops.first() {
+            // Nothing before a reset can be replayed on top of it
+            history.batches.clear();
+            history.bytes = 0;
+            history.trimmed_through = Some(*revision);
+        }
+        let batch = Arc::new(batch);
+        history.bytes += batch.bytes();
+        history.batches.push_back(batch.clone());
+        // A batch bigger than the whole budget goes too, it's still sent to who's listening
+        while history.batches.len() > HISTORY_LEN || history.bytes > self.history_bytes {
+            let dropped = history.batches.pop_front().unwrap();
+            history.bytes -= dropped.bytes();
+            history.trimmed_through = Some(dropped.version);
+        }
+        // Only fails when nobody is watching
+        let _ = self.tx.send(batch);
+    }
+
+    pub fn subscribe(&self, from: Option<i64>) -> Result<Subscription, Compacted> {
+        let history = self.history.lock().unwrap();
+        let replay = match from {
+            Some(from) => {
+                if let Some(trimmed) = history.trimmed_through {
+                    if from < trimmed {
+                        return Err(Compacted {
+                            oldest: history.batches.front().map_or(trimmed, |b| b.version),
+                        });
+                    }
+                }
+                history
+                    .batches
+                    .iter()
+                    .filter(|b| b.version > from)
+                    .cloned()
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        Ok(Subscription {
+            replay,
+            rx: self.tx.subscribe(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Item;
+
+    fn put(version: i64, bytes: usize) -> Batch {
+        Batch {
+            version,
+            ops: vec![Op::Put {
+                key: b"k".to_vec(),
+                item: Item {
+                    version,
+                    data: vec![0; bytes - 1],
+                    expires_at: None,
+                    meta: Default::default(),
+                },
+            }],
+        }
+    }
+
+    #[test]
+    fn trims_history_by_bytes() {
+        let mut watcher = Watcher::new(0);
+        watcher.history_bytes = 300;
+        for version in 1..=4 {
+            watcher.publish(put(version, 100));
+        }
+        let replay = watcher.subscribe(Some(1)).unwrap().replay;
+        let versions: Vec<i64> = replay.iter().map(|batch| batch.version).collect();
+        assert_eq!(versions, [2, 3, 4]);
+        assert!(watcher.subscribe(Some(0)).is_err());
+
+        watcher.publish(put(5, 301));
+        assert!(watcher.subscribe(Some(4)).is_err());
+        assert!(watcher.subscribe(Some(5)).unwrap().replay.is_empty());
+        assert_eq!(watcher.history.lock().unwrap().bytes, 0);
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::parser::{Expiration, SetOptions};
+
+/// The keyspace. A key past its expiry is dropped the next time it's looked at, and
+/// `evict_expired` sweeps up the ones nobody looks at.
+#[derive(Debug, Default)]
+pub struct Db {
+    keyspace: Mutex<Keyspace>,
+}
+
+#[derive(Debug, Default)]
+struct Keyspace {
+    entries: HashMap<Vec<u8>, Entry>,
+    /// Every key with an expiry, soonest first
+    expiring: BTreeSet<(u64, Vec<u8>)>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    /// Unix millis
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// What a SET did
+#[derive(Debug, PartialEq)]
+pub struct SetOutcome {
+    /// False when NX or XX stopped it
+    pub written: bool,
+    /// The value from before, for SET ... GET
+    pub old: Option<Vec<u8>>,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl Db {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut keyspace = self.keyspace.lock().unwrap();
+        keyspace.live(key, now_millis()).map(|entry| entry.value.clone())
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8], options: &SetOptions) -> SetOutcome {
+        let now = now_millis();
+        let mut keyspace = self.keyspace.lock().unwrap();
+        let old = keyspace.live(key, now);
+        let exists = old.is_some();
+        let old_expires_at = old.as_ref().and_then(|entry| entry.expires_at);
+        let old = old.map(|entry| entry.value.clone());
+        if (options.nx && exists) || (options.xx && !exists) {
+            return SetOutcome {
+                written: false,
+                old,
+            };
+        }
+
+        let expires_at = match options.expiration {
+            None => None,
+            Some(Expiration::Ex(seconds)) => Some(now.saturating_add(seconds.saturating_mul(1000))),
+            Some(Expiration::Px(millis)) => Some(now.saturating_add(millis)),
+            Some(Expiration::ExAt(unix_time)) => Some(unix_time.saturating_mul(1000)),
+            Some(Expiration::PxAt(unix_time)) => Some(unix_time),
+            Some(Expiration::KeepTtl) => old_expires_at,
+        };
+        keyspace.remove(key);
+        if let Some(at) = expires_at {
+            keyspace.expiring.insert((at, key.to_vec()));
+        }
+        keyspace.entries.insert(
+            key.to_vec(),
+            Entry {
+                value: value.to_vec(),
+                expires_at,
+            },
+        );
+        SetOutcome { written: true, old }
+    }
+
+    /// Drops up to `limit` expired keys, soonest first, returning how many it dropped.
+    /// Only looks at keys that have expired, so it takes as long as they do to drop.
+    pub fn evict_expired(&self, limit: usize) -> usize {
+        let now = now_millis();
+        let mut keyspace = self.keyspace.lock().unwrap();
+        let mut evicted = 0;
+        while evicted < limit && keyspace.expiring.first().is_some_and(|(at, _)| *at <= now) {
+            let (_, key) = keyspace.expiring.pop_first().unwrap();
+            keyspace.entries.remove(&key);
+            evicted += 1;
+        }
+        evicted
+    }
+}
+
+impl Keyspace {
+    /// The entry for `key`, unless it has expired, in which case it's removed
+    fn live(&mut self, key: &[u8], now: u64) -> Option<&Entry> {
+        if self.entries.get(key)?.is_expired(now) {
+            self.remove(key);
+            return None;
+        }
+        self.entries.get(key)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(at) = entry.expires_at {
+            self.expiring.remove(&(at, key.to_vec()));
+        }
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_set_command, RedisCommand};
+
+    fn options(args: &[&str]) -> SetOptions {
+        let args: Vec<String> = ["k", "v"]
+            .iter()
+            .chain(args)
+            .map(|s| s.to_string())
+            .collect();
+        match parse_set_command(&args) {
+            Ok(RedisCommand::Set { options, .. }) => options,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn honors_set_options() {
+        let db = Db::new();
+        let outcome = db.set(b"k", b"1", &options(&["XX"]));
+        assert_eq!((outcome.written, outcome.old), (false, None));
+        assert_eq!(db.get(b"k"), None);
+
+        assert!(db.set(b"k", b"1", &options(&["NX"])).written);
+        let outcome = db.set(b"k", b"2", &options(&["NX", "GET"]));
+        assert_eq!((outcome.written, outcome.old), (false, Some(b"1".to_vec())));
+        let outcome = db.set(b"k", b"2", &options(&["XX", "GET"]));
+        assert_eq!((outcome.written, outcome.old), (true, Some(b"1".to_vec())));
+        assert_eq!(db.get(b"k"), Some(b"2".to_vec()));
+
+        // Already expired, so gone as soon as it's looked at
+        db.set(b"k", b"3", &options(&["PXAT", "1"]));
+        assert_eq!(db.get(b"k"), None);
+        assert!(db.set(b"k", b"4", &options(&["NX", "EX", "60"])).written);
+        db.set(b"k", b"5", &options(&["KEEPTTL"]));
+        let keyspace = db.keyspace.lock().unwrap();
+        assert!(keyspace.entries[b"k".as_slice()].expires_at.unwrap() > now_millis());
+        assert_eq!(keyspace.expiring.len(), 1);
+    }
+
+    #[test]
+    fn evicts_expired_keys() {
+        let db = Db::new();
+        db.set(b"a", b"1", &options(&["PX", "1"]));
+        db.set(b"b", b"1", &options(&["EX", "60"]));
+        db.set(b"c", b"1", &options(&[]));
+        db.set(b"d", b"1", &options(&["PX", "1"]));
+        db.set(b"e", b"1", &options(&["PX", "1"]));
+        // No longer expiring, so nothing to evict
+        db.set(b"e", b"2", &options(&[]));
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(db.evict_expired(1), 1);
+        assert_eq!(db.evict_expired(10), 1);
+        assert_eq!(db.evict_expired(10), 0);
+        assert_eq!(db.get(b"e"), Some(b"2".to_vec()));
+        assert_eq!(db.keyspace.lock().unwrap().entries.len(), 3);
+    }
+}
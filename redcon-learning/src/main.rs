@@ -1,10 +1,22 @@
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
 
-use redcon_learning::parser::parse_set_command;
+use redcon_learning::db::Db;
+use redcon_learning::parser::{parse_set_command, RedisCommand};
+
+// How often expired keys that nobody reads are swept out
+const EVICT_INTERVAL: Duration = Duration::from_millis(100);
+// The most a sweep drops while holding the lock, the rest wait for the next one
+const MAX_EVICTIONS: usize = 1000;
 
 fn main() {
-    let db: Mutex<HashMap<Vec<u8>, Vec<u8>>> = Mutex::new(HashMap::new());
+    let db = Arc::new(Db::new());
+
+    let sweeper = db.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(EVICT_INTERVAL);
+        sweeper.evict_expired(MAX_EVICTIONS);
+    });
 
     let mut s = redcon::listen("127.0.0.1:6380", db).unwrap();
     s.command = Some(|conn, db, args| {
@@ -17,8 +29,6 @@ fn main() {
                     return;
                 }
                 print_vec_vec_u8(&args);
-                let mut db = db.lock().unwrap();
-                db.insert(args[1].to_owned(), args[2].to_owned());
 
                 let start = std::time::Instant::now();
                 let parsed = parse_set_command(
@@ -31,7 +41,26 @@ fn main() {
                 println!("parsed: {:?}", parsed);
                 println!("Parsing time: {:?}", duration);
 
-                conn.write_bulk("msg".as_bytes());
+                let options = match parsed {
+                    Ok(RedisCommand::Set { options, .. }) => options,
+                    Ok(_) => unreachable!("parse_set_command only returns Set"),
+                    Err(e) => {
+                        conn.write_error(&format!("ERR {}", e));
+                        return;
+                    }
+                };
+                // The parsed key and value went through from_utf8_lossy, so use the raw ones
+                let outcome = db.set(&args[1], &args[2], &options);
+                if options.get {
+                    match outcome.old {
+                        Some(old) => conn.write_bulk(&old),
+                        None => conn.write_null(),
+                    }
+                } else if outcome.written {
+                    conn.write_string("OK");
+                } else {
+                    conn.write_null();
+                }
             }
             "get" => {
                 if args.len() < 2 {
@@ -39,9 +68,8 @@ fn main() {
                     return;
                 }
                 print_vec_vec_u8(&args);
-                let db = db.lock().unwrap();
                 match db.get(&args[1]) {
-                    Some(val) => conn.write_bulk(val),
+                    Some(val) => conn.write_bulk(&val),
                     None => conn.write_null(),
                 }
             }
@@ -14,10 +14,10 @@ pub enum RedisCommand {
 /// Could use the redis::SetOptions instead
 #[derive(Debug, Default)]
 pub struct SetOptions {
-    nx: bool,                       // NX: Only set if the key does not exist
-    xx: bool,                       // XX: Only set if the key exists
-    get: bool,                      // GET: Return the old value
-    expiration: Option<Expiration>, // Expiration options
+    pub nx: bool,                       // NX: Only set if the key does not exist
+    pub xx: bool,                       // XX: Only set if the key exists
+    pub get: bool,                      // GET: Return the old value
+    pub expiration: Option<Expiration>, // Expiration options
 }
 
 #[derive(Debug)]
@@ -64,7 +64,7 @@ pub fn parse_set_command(args: &[String]) -> Result<RedisCommand, String> {
                     return Err("EX requires a seconds value.".to_string());
                 }
                 if let Ok(seconds) = args[i + 1].parse::<u64>() {
-                    options.expiration = Some(Expiration::Ex(seconds));
+                    set_expiration(&mut options, Expiration::Ex(seconds))?;
                     i += 1;
                 } else {
                     return Err("Invalid EX seconds value.".to_string());
@@ -75,7 +75,7 @@ pub fn parse_set_command(args: &[String]) -> Result<RedisCommand, String> {
                     return Err("PX requires a milliseconds value.".to_string());
                 }
                 if let Ok(milliseconds) = args[i + 1].parse::<u64>() {
-                    options.expiration = Some(Expiration::Px(milliseconds));
+                    set_expiration(&mut options, Expiration::Px(milliseconds))?;
                     i += 1;
                 } else {
                     return Err("Invalid PX milliseconds value.".to_string());
@@ -86,7 +86,7 @@ pub fn parse_set_command(args: &[String]) -> Result<RedisCommand, String> {
                     return Err("EXAT requires a Unix timestamp in seconds.".to_string());
                 }
                 if let Ok(unix_time) = args[i + 1].parse::<u64>() {
-                    options.expiration = Some(Expiration::ExAt(unix_time));
+                    set_expiration(&mut options, Expiration::ExAt(unix_time))?;
                     i += 1;
                 } else {
                     return Err("Invalid EXAT Unix timestamp value.".to_string());
@@ -97,17 +97,14 @@ pub fn parse_set_command(args: &[String]) -> Result<RedisCommand, String> {
                     return Err("PXAT requires a Unix timestamp in milliseconds.".to_string());
                 }
                 if let Ok(unix_time) = args[i + 1].parse::<u64>() {
-                    options.expiration = Some(Expiration::PxAt(unix_time));
+                    set_expiration(&mut options, Expiration::PxAt(unix_time))?;
                     i += 1;
                 } else {
                     return Err("Invalid PXAT Unix timestamp value.".to_string());
                 }
             }
             "KEEPTTL" => {
-                if options.expiration.is_some() {
-                    return Err("Cannot specify KEEPTTL with other expiration options.".to_string());
-                }
-                options.expiration = Some(Expiration::KeepTtl);
+                set_expiration(&mut options, Expiration::KeepTtl)?;
             }
             _ => return Err(format!("Unknown option: {}", args[i])),
         }
@@ -120,3 +117,17 @@ pub fn parse_set_command(args: &[String]) -> Result<RedisCommand, String> {
         options,
     })
 }
+
+/// Only one of EX, PX, EXAT, PXAT and KEEPTTL can be given, and times have to be positive
+fn set_expiration(options: &mut SetOptions, expiration: Expiration) -> Result<(), String> {
+    if options.expiration.is_some() {
+        return Err("Cannot specify more than one expiration option.".to_string());
+    }
+    if let Expiration::Ex(0) | Expiration::Px(0) | Expiration::ExAt(0) | Expiration::PxAt(0) =
+        expiration
+    {
+        return Err("Invalid expire time in SET command.".to_string());
+    }
+    options.expiration = Some(expiration);
+    Ok(())
+}